@@ -1,9 +1,12 @@
 use std::borrow::{Borrow, BorrowMut};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::cmp::Ordering;
 use std::rc::Rc;
 use ordered_float::OrderedFloat;
+use crate::api::MaskSeq;
 use crate::mask::Mask;
+use crate::reconstruct::Image;
+use crate::transform::inverse_haar_2d;
 
 #[derive(PartialEq, Eq, Clone)]
 enum Quadtree<T> {
@@ -45,28 +48,35 @@ impl<T> Quadtree<T> {
         }
     }
 
-    // pub fn index(&self, key: &Key<T>) -> Option<&Quadtree<T>> {
-    //     let mut tree: &Quadtree<T> = &self;
-    //     let mut slice: &[u32] = &key.indices[..];
-    //     loop {
-    //         if let Some((first, rest)) = slice.split_first() {
-    //             tree = &(tree.children()?)[*first as usize];
-    //             slice = rest;
-    //         } else {
-    //             break Some(tree);
-    //         }
-    //     }
-    // }
+    pub fn index_mut(&mut self, key: &Key<T>) -> Option<&mut Quadtree<T>> {
+        let mut tree: &mut Quadtree<T> = self;
+        for index in &key.indices {
+            tree = &mut tree.children_mut()?[*index as usize];
+        }
+        Some(tree)
+    }
 }
 
-#[derive(Clone, PartialEq, Eq)]
-struct Key<T> {
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Key<T> {
     phantom: std::marker::PhantomData<T>,
     indices: Vec<u32>
 }
 
-#[derive(PartialEq, Eq, Clone)]
-struct Rect {
+impl<T> Key<T> {
+    fn root() -> Self {
+        Key { phantom: std::marker::PhantomData, indices: Vec::new() }
+    }
+
+    fn child(&self, index: u32) -> Self {
+        let mut indices = self.indices.clone();
+        indices.push(index);
+        Key { phantom: std::marker::PhantomData, indices }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct Rect {
     bottom_left: (u32, u32),
     size: (u32, u32),
 }
@@ -103,9 +113,13 @@ impl Rect {
     }
 
     fn contains(&self, x: u32, y: u32) -> bool {
-        let between = |n: u32, l: u32, u: u32| -> bool { (l <= n) && (n <= u) };
-        between(x - self.bottom_left.0, 0, self.size.0 - 1)
-            && between(y - self.bottom_left.1, 0, self.size.1 - 1)
+        let between = |n: u32, l: u32, size: u32| -> bool { (l <= n) && (n < l + size) };
+        between(x, self.bottom_left.0, self.size.0)
+            && between(y, self.bottom_left.1, self.size.1)
+    }
+
+    fn area(&self) -> u32 {
+        self.size.0 * self.size.1
     }
 
     fn to_mask(&self, width: u32, height: u32) -> Mask {
@@ -119,18 +133,28 @@ impl Rect {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
-struct Energy(OrderedFloat<f64>);
+/// Mean detector count while a mask was displayed.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
+pub struct Energy(pub OrderedFloat<f64>);
+
+impl Energy {
+    // Placeholder payload for nodes that haven't been measured yet.
+    const UNMEASURED: Energy = Energy(OrderedFloat(-1.0));
+}
 
 #[derive(PartialEq, Eq)]
 struct QueueElement {
+    energy: Energy,
     node: Key<Energy>,
     rect: Rect,
 }
 
+// Brightest regions come out of the `BinaryHeap` first.
 impl Ord for QueueElement {
     fn cmp(&self, other: &Self) -> Ordering {
-        todo!() // other.node.payload().cmp(&self.node.payload())
+        self.energy.cmp(&other.energy)
+            .then_with(|| other.node.cmp(&self.node))
+            .then_with(|| other.rect.cmp(&self.rect))
     }
 }
 
@@ -140,86 +164,448 @@ impl PartialOrd for QueueElement {
     }
 }
 
-#[derive(Clone)]
-struct Options {
-    width: u32,
-    height: u32,
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub width: u32,
+    pub height: u32,
+    /// Maximum number of masks to display.
+    pub budget: usize,
+    /// Regions (or wavelet coefficients) measuring less than this are not
+    /// refined further.
+    pub threshold: f64,
+}
+
+impl Options {
+    /// The search parameters of an adaptive mask sequence.
+    pub fn from_sequence(seq: &MaskSeq) -> Option<Options> {
+        match *seq {
+            MaskSeq::AdaptiveQuadtree { resolution, budget, threshold }
+            | MaskSeq::AdaptiveHaar { resolution, budget, threshold } => {
+                Some(Options {
+                    width: resolution as u32,
+                    height: resolution as u32,
+                    budget,
+                    threshold,
+                })
+            },
+            _ => None,
+        }
+    }
 }
 
-trait Adaptive {
+/// A measurement strategy that picks each mask based on the results of the
+/// previous ones. Calls to `next` and `measurement` alternate.
+pub trait Adaptive {
     type MeasurementHandle;
     fn new(options: &Options) -> Self;
     fn next(&mut self) -> Option<(Mask, Self::MeasurementHandle)>;
     fn measurement(&mut self, handle: &Self::MeasurementHandle, energy: Energy);
+    fn reconstruct(&self) -> Image;
+    /// What adding `energy` to every measurement so far adds to the image,
+    /// such as the detector's dark rate.
+    fn offset(&self, energy: f64) -> Image;
+}
+
+/// Picks masks one at a time from the counts measured with earlier ones, as
+/// `capture` displays them.
+pub trait Sequencer {
+    type Handle;
+    fn next_mask(&mut self) -> Option<(Mask, Self::Handle)>;
+    /// Count rate while the mask was shown.
+    fn record(&mut self, handle: &Self::Handle, mean: f64);
+}
+
+impl<A: Adaptive> Sequencer for A {
+    type Handle = A::MeasurementHandle;
+
+    fn next_mask(&mut self) -> Option<(Mask, Self::Handle)> {
+        self.next()
+    }
+
+    fn record(&mut self, handle: &Self::Handle, mean: f64) {
+        self.measurement(handle, Energy(OrderedFloat(mean)));
+    }
+}
+
+/// Re-runs a search offline against recorded measurements, which reproduces
+/// the masks that were displayed, and reconstructs the image. The search
+/// picked masks by the rates it counted, so `dark` is only taken off the
+/// image. `None` if a mask the search asked for wasn't measured, as the masks
+/// after it can't be known.
+pub fn replay<A: Adaptive>(options: &Options, values: &[Option<f64>], dark: f64) -> Option<Image> {
+    let mut search = A::new(options);
+    for value in values {
+        match search.next() {
+            Some((_, handle)) => search.measurement(&handle, Energy(OrderedFloat((*value)?))),
+            None => break,
+        }
+    }
+    Some(search.reconstruct() - search.offset(dark))
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Region {
+    key: Key<Energy>,
+    rect: Rect,
+}
+
+/// Measures the whole field, then repeatedly splits the brightest measured
+/// region into quadrants until it is a single cell, falls below the
+/// threshold, or the budget runs out. Sparse star fields need only a few
+/// masks per star instead of one per cell.
+pub struct QuadtreeSearch {
+    options: Options,
+    root: Quadtree<Energy>,
+    heap: BinaryHeap<QueueElement>,
+    pending: VecDeque<Region>,
+    issued: usize,
+}
+
+impl QuadtreeSearch {
+    fn image(&self, root: &Quadtree<Energy>) -> Image {
+        let mut image = Image::zeros(
+            (self.options.height as usize, self.options.width as usize));
+        let rect = Rect {
+            bottom_left: (0, 0),
+            size: (self.options.width, self.options.height),
+        };
+        self.fill(root, &rect, &mut image);
+        image
+    }
+
+    fn fill(&self, node: &Quadtree<Energy>, rect: &Rect, image: &mut Image) {
+        let energy = node.payload().0.0;
+        if energy >= 0.0 {
+            let density = energy / (rect.area() as f64);
+            for y in rect.bottom_left.1 .. rect.bottom_left.1 + rect.size.1 {
+                for x in rect.bottom_left.0 .. rect.bottom_left.0 + rect.size.0 {
+                    image[(y as usize, x as usize)] = density;
+                }
+            }
+        }
+        // Unmeasured children keep their parent's average.
+        if let (Some(children), Some(rects)) = (node.children(), rect.subdivide()) {
+            for (child, child_rect) in children.iter().zip(rects.iter()) {
+                self.fill(child, child_rect, image);
+            }
+        }
+    }
+}
+
+impl Adaptive for QuadtreeSearch {
+    type MeasurementHandle = Region;
+
+    fn new(options: &Options) -> Self {
+        let mut pending = VecDeque::new();
+        pending.push_back(Region {
+            key: Key::root(),
+            rect: Rect {
+                bottom_left: (0, 0),
+                size: (options.width, options.height),
+            },
+        });
+        QuadtreeSearch {
+            options: options.clone(),
+            root: Quadtree::Leaf { payload: Energy::UNMEASURED },
+            heap: BinaryHeap::new(),
+            pending,
+            issued: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<(Mask, Region)> {
+        if self.issued >= self.options.budget {
+            return None;
+        }
+        while self.pending.is_empty() {
+            let best = self.heap.pop()?;
+            if best.energy.0.0 < self.options.threshold {
+                // Everything left in the heap is dimmer still.
+                return None;
+            }
+            let subdivided = match best.rect.subdivide() {
+                Some(rects) => rects,
+                None => continue,
+            };
+            let node = self.root.index_mut(&best.node).unwrap();
+            *node = Quadtree::Branch {
+                payload: *node.payload(),
+                children: Box::new([
+                    Quadtree::Leaf { payload: Energy::UNMEASURED },
+                    Quadtree::Leaf { payload: Energy::UNMEASURED },
+                    Quadtree::Leaf { payload: Energy::UNMEASURED },
+                    Quadtree::Leaf { payload: Energy::UNMEASURED },
+                ]),
+            };
+            for (i, rect) in subdivided.into_iter().enumerate() {
+                self.pending.push_back(Region {
+                    key: best.node.child(i as u32),
+                    rect,
+                });
+            }
+        }
+        let region = self.pending.pop_front().unwrap();
+        self.issued += 1;
+        Some((region.rect.to_mask(self.options.width, self.options.height),
+              region))
+    }
+
+    fn measurement(&mut self, region: &Region, energy: Energy) {
+        *self.root.index_mut(&region.key).unwrap().payload_mut() = energy;
+        self.heap.push(QueueElement {
+            energy,
+            node: region.key.clone(),
+            rect: region.rect.clone(),
+        });
+    }
+
+    fn reconstruct(&self) -> Image {
+        self.image(&self.root)
+    }
+
+    fn offset(&self, energy: f64) -> Image {
+        // The same regions, each measuring just the offset.
+        fn replace(node: &mut Quadtree<Energy>, energy: Energy) {
+            if node.payload().0.0 >= 0.0 {
+                *node.payload_mut() = energy;
+            }
+            for child in node.children_mut().into_iter().flatten() {
+                replace(child, energy);
+            }
+        }
+        let mut root = self.root.clone();
+        replace(&mut root, Energy(OrderedFloat(energy)));
+        self.image(&root)
+    }
+}
+
+/// Which half of a wavelet a mask shows; the LCD can't display negative
+/// values, so each detail coefficient is the difference of two masks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Part {
+    Positive,
+    Negative,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Coefficient {
+    position: (usize, usize),
+    part: Part,
+}
+
+/// Measures the coarsest Haar coefficients, then the four children of each
+/// significant coefficient in order of magnitude, and reconstructs by the
+/// inverse transform with unmeasured coefficients set to zero. Smooth areas
+/// stop refining early, so measurements concentrate on edges.
+///
+/// Coefficients are laid out as by `transform::haar_2d`, where the children
+/// of detail coefficient `(y, x)` are `(2y + dy, 2x + dx)`.
+pub struct HaarSearch {
+    options: Options,
+    coefficients: Image,
+    // Positive halves of coefficients whose negative half is still pending.
+    partial: HashMap<(usize, usize), f64>,
+    heap: BinaryHeap<(Energy, (usize, usize))>,
+    pending: VecDeque<Coefficient>,
+    issued: usize,
+}
+
+impl HaarSearch {
+    fn n(&self) -> usize {
+        self.options.width as usize
+    }
+
+    fn wavelet(&self, position: (usize, usize)) -> Image {
+        let mut wavelet = Image::zeros((self.n(), self.n()));
+        wavelet[position] = 1.0;
+        inverse_haar_2d(&mut wavelet);
+        wavelet
+    }
+
+    // Haar wavelets take a single magnitude over their support.
+    fn scale(&self, position: (usize, usize)) -> f64 {
+        self.wavelet(position).fold(0.0, |m: f64, v| m.max(v.abs()))
+    }
+
+    fn push_measurements(&mut self, position: (usize, usize)) {
+        for part in [Part::Positive, Part::Negative] {
+            self.pending.push_back(Coefficient { position, part });
+        }
+    }
 }
 
-// struct QuadtreeSearch<'a> {
-//     options: Options,
-//     heap: BinaryHeap<QueueElement<'a>>
-// }
-
-// fn adaptive(
-//     width: u32,
-//     height: u32,
-//     measure: impl Fn(&Mask) -> f64,
-//     stop: impl Fn(&Rect, f64) -> bool,
-// ) -> Mask {
-//     let mut priority_queue = BinaryHeap::<QueueElement<'_>>::new();
-//     let all_white =
-//         image::ImageBuffer::from_pixel(width, height, image::Luma([255]));
-//     let mut root = Quadtree::Leaf {
-//         payload: Energy(OrderedFloat(measure(&all_white))),
-//     };
-//     priority_queue.push(QueueElement {
-//         node: &mut root,
-//         rect: Rect {
-//             bottom_left: (0, 0),
-//             size: (width, height),
-//         },
-//     });
-//     while let Some(best) = priority_queue.pop() {
-//         if stop(&best.rect, best.node.payload().0.0) {
-//             continue;
-//         }
-//
-//         let subdivided = best.rect.subdivide();
-//         if subdivided.is_none() {
-//             continue;
-//         }
-//         let subdivided = subdivided.unwrap();
-//
-//         let mut children = [
-//             Quadtree::Leaf { payload: Energy(OrderedFloat(-1.0)) },
-//             Quadtree::Leaf { payload: Energy(OrderedFloat(-1.0)) },
-//             Quadtree::Leaf { payload: Energy(OrderedFloat(-1.0)) },
-//             Quadtree::Leaf { payload: Energy(OrderedFloat(-1.0)) },
-//         ];
-//         for (i, rect) in subdivided.iter().enumerate() {
-//             *(children[i].payload_mut()) =
-//                 Energy(OrderedFloat(measure(&rect.to_mask(width, height))));
-//         }
-//         *best.node = Quadtree::Branch {
-//             payload: *best.node.payload(),
-//             children: Box::new(children)
-//         };
-//         let [ref mut c1, ref mut c2, ref mut c3, ref mut c4] =
-//             best.node.children_mut().unwrap();
-//         priority_queue.push(QueueElement {
-//             node: c1,
-//             rect: subdivided[0].clone(),
-//         });
-//         priority_queue.push(QueueElement {
-//             node: c2,
-//             rect: subdivided[1].clone(),
-//         });
-//         priority_queue.push(QueueElement {
-//             node: c3,
-//             rect: subdivided[2].clone(),
-//         });
-//         priority_queue.push(QueueElement {
-//             node: c4,
-//             rect: subdivided[3].clone(),
-//         });
-//     }
-//     todo!()
-// }
+impl Adaptive for HaarSearch {
+    type MeasurementHandle = Coefficient;
+
+    fn new(options: &Options) -> Self {
+        let n = options.width as usize;
+        assert!(options.width == options.height && n.is_power_of_two() && n >= 2,
+                "Haar search needs a square power of two grid, not {}×{}",
+                options.width, options.height);
+        let mut search = HaarSearch {
+            options: options.clone(),
+            coefficients: Image::zeros((n, n)),
+            partial: HashMap::new(),
+            heap: BinaryHeap::new(),
+            pending: VecDeque::new(),
+            issued: 0,
+        };
+        // The scaling coefficient is the plain total.
+        search.pending.push_back(Coefficient { position: (0, 0), part: Part::Positive });
+        for position in [(0, 1), (1, 0), (1, 1)] {
+            search.push_measurements(position);
+        }
+        search
+    }
+
+    fn next(&mut self) -> Option<(Mask, Coefficient)> {
+        if self.issued >= self.options.budget {
+            return None;
+        }
+        while self.pending.is_empty() {
+            let (magnitude, (y, x)) = self.heap.pop()?;
+            if magnitude.0.0 < self.options.threshold {
+                return None;
+            }
+            if 2 * y >= self.n() || 2 * x >= self.n() {
+                // Finest level.
+                continue;
+            }
+            for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                self.push_measurements((2 * y + dy, 2 * x + dx));
+            }
+        }
+        let coefficient = self.pending.pop_front().unwrap();
+        self.issued += 1;
+        let wavelet = self.wavelet(coefficient.position);
+        let mask = Mask::from_fn(self.options.width, self.options.height, |x, y| {
+            let value = wavelet[(y as usize, x as usize)];
+            let shown = match coefficient.part {
+                Part::Positive => value > 0.0,
+                Part::Negative => value < 0.0,
+            };
+            image::Luma([if shown { 255 } else { 0 }])
+        });
+        Some((mask, coefficient))
+    }
+
+    fn measurement(&mut self, coefficient: &Coefficient, energy: Energy) {
+        let position = coefficient.position;
+        let scale = self.scale(position);
+        let energy = energy.0.0;
+        match coefficient.part {
+            Part::Positive if position == (0, 0) => {
+                self.coefficients[position] = scale * energy;
+            },
+            Part::Positive => {
+                self.partial.insert(position, energy);
+            },
+            Part::Negative => {
+                let positive = self.partial.remove(&position).unwrap_or(0.0);
+                let value = scale * (positive - energy);
+                self.coefficients[position] = value;
+                self.heap.push((Energy(OrderedFloat(value.abs())), position));
+            },
+        }
+    }
+
+    fn reconstruct(&self) -> Image {
+        let mut image = self.coefficients.clone();
+        inverse_haar_2d(&mut image);
+        image
+    }
+
+    fn offset(&self, energy: f64) -> Image {
+        // Detail coefficients are differences, which cancel it.
+        let mut image = Image::zeros((self.n(), self.n()));
+        if self.issued > 0 {
+            image[(0, 0)] = self.scale((0, 0)) * energy;
+        }
+        inverse_haar_2d(&mut image);
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: usize = 64;
+
+    fn star_field() -> Image {
+        let mut scene = Image::zeros((N, N));
+        scene[(5, 9)] = 100.0;
+        scene[(40, 33)] = 60.0;
+        scene[(50, 58)] = 80.0;
+        scene
+    }
+
+    fn options() -> Options {
+        Options { width: N as u32, height: N as u32, budget: 200, threshold: 1.0 }
+    }
+
+    // Runs `search` against a detector counting `dark` on top of the light
+    // through each mask, returning the measurements.
+    fn simulate<A: Adaptive>(search: &mut A, scene: &Image, dark: f64) -> Vec<Option<f64>> {
+        let mut values = Vec::new();
+        while let Some((mask, handle)) = search.next() {
+            let energy: f64 = dark + mask.enumerate_pixels()
+                .map(|(x, y, p)| {
+                    (p.0[0] as f64) / 255.0 * scene[(y as usize, x as usize)]
+                })
+                .sum::<f64>();
+            search.measurement(&handle, Energy(OrderedFloat(energy)));
+            values.push(Some(energy));
+        }
+        values
+    }
+
+    #[test]
+    fn quadtree_finds_stars_in_few_measurements() {
+        let mut search = QuadtreeSearch::new(&options());
+        let values = simulate(&mut search, &star_field(), 0.0);
+        assert!(values.len() < 100, "used {} measurements", values.len());
+        assert_eq!(search.reconstruct(), star_field());
+        assert_eq!(replay::<QuadtreeSearch>(&options(), &values, 0.0), Some(star_field()));
+    }
+
+    #[test]
+    fn replay_takes_dark_off_the_image() {
+        // A star only bright enough to refine on with the dark rate added;
+        // replaying rates with it already subtracted would stop short of
+        // the star and leave measurements over.
+        let dark = 0.5;
+        let mut scene = star_field();
+        scene[(20, 44)] = 0.7;
+        let mut search = QuadtreeSearch::new(&options());
+        let values = simulate(&mut search, &scene, dark);
+        assert!(values.len() < options().budget, "used {} measurements", values.len());
+        let replayed = replay::<QuadtreeSearch>(&options(), &values, dark).unwrap();
+        assert_eq!(replayed, search.reconstruct() - search.offset(dark));
+        let error = (&replayed - &scene).fold(0.0, |m: f64, v| m.max(v.abs()));
+        assert!(error < 1e-9, "error {}", error);
+
+        let options = Options { budget: N * N, threshold: 1e-6, ..options() };
+        let mut search = HaarSearch::new(&options);
+        let values = simulate(&mut search, &scene, dark);
+        let replayed = replay::<HaarSearch>(&options, &values, dark).unwrap();
+        let error = (&replayed - &scene).fold(0.0, |m: f64, v| m.max(v.abs()));
+        assert!(error < 1e-9, "error {}", error);
+    }
+
+    #[test]
+    fn haar_search_refines_only_near_edges() {
+        // A flat square on a flat background.
+        let scene = Image::from_shape_fn((N, N), |(y, x)| {
+            if (13 .. 42).contains(&x) && (21 .. 50).contains(&y) { 7.0 } else { 2.0 }
+        });
+        let options = Options { budget: N * N, threshold: 1e-6, ..options() };
+        let mut search = HaarSearch::new(&options);
+        let values = simulate(&mut search, &scene, 0.0);
+        assert!(values.len() < N * N / 2, "used {} measurements", values.len());
+        let error = (&search.reconstruct() - &scene).fold(0.0, |m: f64, v| m.max(v.abs()));
+        assert!(error < 1e-9, "error {}", error);
+        let replayed = replay::<HaarSearch>(&options, &values, 0.0).unwrap();
+        assert_eq!(replayed, search.reconstruct());
+    }
+}
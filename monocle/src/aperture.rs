@@ -0,0 +1,354 @@
+use image::Luma;
+use log::*;
+use crate::adaptive::Sequencer;
+use crate::mask::Mask;
+use crate::profile::ApertureCalibration;
+
+/// Side of the cells probe masks are drawn with, in pixels.
+pub const CELL: u32 = 8;
+/// Side of the blocks of the coarse scan, in cells.
+const BLOCK: u32 = 20;
+/// Side of the probe moved along each ray, in cells.
+const PROBE: u32 = 2;
+/// Number of directions the edge is searched along.
+const RAYS: usize = 24;
+/// Fraction of the way from dark to the brightest block, or to the probe at
+/// the centre, at which a block or probe counts as inside the aperture.
+const LIT: f64 = 0.5;
+
+/// What a probe mask measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Dark,
+    Block(usize),
+    Center,
+    /// The probe at the middle of a ray's interval.
+    Ray(usize),
+}
+
+// The edge lies between `lit` and `dark` pixels from the centre; `limit` is
+// where the ray leaves the screen.
+#[derive(Debug, Clone, Copy)]
+struct Ray {
+    direction: (f64, f64),
+    lit: f64,
+    dark: f64,
+    limit: f64,
+}
+
+/// Finds the telescope's image on the panel: measures coarse blocks, then
+/// bisects the position of a small probe along rays from the centroid of
+/// the lit blocks until each edge point is known to within a cell.
+#[derive(Debug, Clone)]
+pub struct ApertureSearch {
+    /// Grid of cells covering the screen.
+    cells: (u32, u32),
+    dark: Option<f64>,
+    blocks: Vec<f64>,
+    center: Option<(f64, f64)>,
+    reference: Option<f64>,
+    rays: Vec<Ray>,
+}
+
+impl ApertureSearch {
+    /// A search over a screen of `size` pixels, drawn as cells of [`CELL`]
+    /// pixels.
+    pub fn new(size: (u32, u32)) -> ApertureSearch {
+        ApertureSearch {
+            cells: (size.0 / CELL, size.1 / CELL),
+            dark: None,
+            blocks: Vec::new(),
+            center: None,
+            reference: None,
+            rays: Vec::new(),
+        }
+    }
+
+    /// Size of the region the masks cover, in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        (self.cells.0 * CELL, self.cells.1 * CELL)
+    }
+
+    fn blocks_across(&self) -> (u32, u32) {
+        ((self.cells.0 + BLOCK - 1) / BLOCK, (self.cells.1 + BLOCK - 1) / BLOCK)
+    }
+
+    // First and one past the last cell of a block.
+    fn block_cells(&self, index: usize) -> ((u32, u32), (u32, u32)) {
+        let across = self.blocks_across().0;
+        let (x, y) = (index as u32 % across * BLOCK, index as u32 / across * BLOCK);
+        ((x, y), ((x + BLOCK).min(self.cells.0), (y + BLOCK).min(self.cells.1)))
+    }
+
+    // A mask lighting the cells from `from` up to `to`, clipped to the grid.
+    fn rectangle(&self, from: (i64, i64), to: (i64, i64)) -> Mask {
+        let mut mask = Mask::new(self.cells.0, self.cells.1);
+        let clip = |v: i64, max: u32| v.clamp(0, max as i64) as u32;
+        for y in clip(from.1, self.cells.1) .. clip(to.1, self.cells.1) {
+            for x in clip(from.0, self.cells.0) .. clip(to.0, self.cells.0) {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+        mask
+    }
+
+    // A probe centred as near as the grid allows on `at`, in pixels.
+    fn probe(&self, at: (f64, f64)) -> Mask {
+        let corner = |v: f64| (v / CELL as f64 - PROBE as f64 / 2.0).round() as i64;
+        let (x, y) = (corner(at.0), corner(at.1));
+        self.rectangle((x, y), (x + PROBE as i64, y + PROBE as i64))
+    }
+
+    // Centroid of the blocks over half as bright as the brightest, weighted
+    // by their counts above dark.
+    fn centroid(&self) -> Option<(f64, f64)> {
+        let dark = self.dark?;
+        let brightest = self.blocks.iter().cloned().fold(dark, f64::max);
+        if brightest <= dark {
+            warn!("No block was brighter than the dark level {}", dark);
+            return None;
+        }
+        let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+        for (index, value) in self.blocks.iter().enumerate() {
+            let weight = value - dark;
+            if weight < LIT * (brightest - dark) {
+                continue;
+            }
+            let (from, to) = self.block_cells(index);
+            x += weight * (from.0 + to.0) as f64 / 2.0;
+            y += weight * (from.1 + to.1) as f64 / 2.0;
+            total += weight;
+        }
+        let cell = CELL as f64;
+        Some((cell * x / total, cell * y / total))
+    }
+
+    fn start_rays(&mut self, center: (f64, f64)) {
+        let (width, height) = self.size();
+        let exit = |position: f64, direction: f64, size: u32| {
+            if direction > 1e-9 {
+                (size as f64 - position) / direction
+            } else if direction < -1e-9 {
+                -position / direction
+            } else {
+                f64::INFINITY
+            }
+        };
+        self.rays = (0 .. RAYS).map(|i| {
+            let angle = 2.0 * std::f64::consts::PI * i as f64 / RAYS as f64;
+            let direction = (angle.cos(), angle.sin());
+            let limit = exit(center.0, direction.0, width)
+                .min(exit(center.1, direction.1, height));
+            Ray { direction, lit: 0.0, dark: limit, limit }
+        }).collect();
+    }
+
+    fn along(center: (f64, f64), ray: &Ray, distance: f64) -> (f64, f64) {
+        (center.0 + distance * ray.direction.0, center.1 + distance * ray.direction.1)
+    }
+
+    /// Edge points found so far, relative to the screen centre. Rays that
+    /// left the screen before reaching the edge are skipped.
+    pub fn edge(&self) -> Vec<(f64, f64)> {
+        let center = match self.center {
+            Some(center) => center,
+            None => return Vec::new(),
+        };
+        let (width, height) = self.size();
+        self.rays.iter()
+            .filter(|ray| ray.lit < ray.limit - CELL as f64)
+            .map(|ray| {
+                let (x, y) = Self::along(center, ray, (ray.lit + ray.dark) / 2.0);
+                (x - width as f64 / 2.0, y - height as f64 / 2.0)
+            })
+            .collect()
+    }
+
+    /// The circle through the edge points and, if they describe one, the
+    /// ellipse around the same centre.
+    pub fn fit(&self) -> Option<ApertureCalibration> {
+        let edge = self.edge();
+        let (center, radius) = fit_circle(&edge)?;
+        Some(ApertureCalibration {
+            center,
+            radius,
+            ellipse: fit_ellipse(&edge, center),
+        })
+    }
+}
+
+impl Sequencer for ApertureSearch {
+    type Handle = Probe;
+
+    fn next_mask(&mut self) -> Option<(Mask, Probe)> {
+        if self.dark.is_none() {
+            return Some((Mask::new(self.cells.0, self.cells.1), Probe::Dark));
+        }
+        let (across, down) = self.blocks_across();
+        let index = self.blocks.len();
+        if index < (across * down) as usize {
+            let (from, to) = self.block_cells(index);
+            let mask = self.rectangle((from.0 as i64, from.1 as i64),
+                                      (to.0 as i64, to.1 as i64));
+            return Some((mask, Probe::Block(index)));
+        }
+        if self.center.is_none() {
+            self.center = Some(self.centroid()?);
+        }
+        let center = self.center.unwrap();
+        if self.reference.is_none() {
+            return Some((self.probe(center), Probe::Center));
+        }
+        let (index, ray) = self.rays.iter().enumerate()
+            .find(|(_, ray)| ray.dark - ray.lit > CELL as f64)?;
+        let at = Self::along(center, ray, (ray.lit + ray.dark) / 2.0);
+        Some((self.probe(at), Probe::Ray(index)))
+    }
+
+    fn record(&mut self, handle: &Probe, value: f64) {
+        match *handle {
+            Probe::Dark => self.dark = Some(value),
+            Probe::Block(_) => self.blocks.push(value),
+            Probe::Center => {
+                self.reference = Some(value);
+                let dark = self.dark.unwrap_or(0.0);
+                if value > dark {
+                    self.start_rays(self.center.unwrap());
+                } else {
+                    warn!("Probe at the block centroid {:?} isn't brighter than dark ({} ≤ {})",
+                          self.center, value, dark);
+                }
+            },
+            Probe::Ray(index) => {
+                let (dark, reference) = (self.dark.unwrap_or(0.0), self.reference.unwrap());
+                let ray = &mut self.rays[index];
+                let middle = (ray.lit + ray.dark) / 2.0;
+                if value - dark >= LIT * (reference - dark) {
+                    ray.lit = middle;
+                } else {
+                    ray.dark = middle;
+                }
+            },
+        }
+    }
+}
+
+// Solves the least squares problem with the given rows and right hand sides
+// through its normal equations, by Gaussian elimination.
+fn least_squares<const N: usize>(rows: impl Iterator<Item = ([f64; N], f64)>)
+                                 -> Option<[f64; N]>
+{
+    let mut a = [[0.0; N]; N];
+    let mut b = [0.0; N];
+    for (row, rhs) in rows {
+        for i in 0 .. N {
+            for j in 0 .. N {
+                a[i][j] += row[i] * row[j];
+            }
+            b[i] += row[i] * rhs;
+        }
+    }
+    for column in 0 .. N {
+        let pivot = (column .. N)
+            .max_by(|i, j| a[*i][column].abs().partial_cmp(&a[*j][column].abs()).unwrap())?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        for row in column + 1 .. N {
+            let factor = a[row][column] / a[column][column];
+            let pivot_row = a[column];
+            for (value, pivot_value) in a[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0 .. N).rev() {
+        let sum: f64 = (row + 1 .. N).map(|j| a[row][j] * x[j]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Centre and radius of the circle `x² + y² + dx + ey + f = 0` closest to
+/// `points` in the algebraic sense (Kåsa's fit).
+pub fn fit_circle(points: &[(f64, f64)]) -> Option<((f64, f64), f64)> {
+    if points.len() < 3 {
+        return None;
+    }
+    let [d, e, f] = least_squares(
+        points.iter().map(|(x, y)| ([*x, *y, 1.0], -(x * x + y * y))))?;
+    let center = (-d / 2.0, -e / 2.0);
+    let squared = center.0 * center.0 + center.1 * center.1 - f;
+    (squared > 0.0).then(|| (center, squared.sqrt()))
+}
+
+/// Coefficients `cxx`, `cyy`, `cxy` of the ellipse `cxx x² + cyy y² + cxy xy = 1`
+/// around `center` closest to `points`, if they describe an ellipse.
+pub fn fit_ellipse(points: &[(f64, f64)], center: (f64, f64)) -> Option<(f64, f64, f64)> {
+    if points.len() < 5 {
+        return None;
+    }
+    let [cxx, cyy, cxy] = least_squares(points.iter().map(|(x, y)| {
+        let (x, y) = (x - center.0, y - center.1);
+        ([x * x, y * y, x * y], 1.0)
+    }))?;
+    crate::sep::aperture::ellipse_axes(cxx, cyy, cxy)?;
+    Some((cxx, cyy, cxy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sep::aperture::ellipse_axes;
+
+    #[test]
+    fn finds_simulated_elliptical_aperture() {
+        let (width, height) = (1440, 2560);
+        // Semi-axes and angle of the image, its centre from the screen
+        // centre, and count rates for the dark panel and per lit pixel.
+        let (a, b, theta) = (420.0f64, 360.0f64, 0.4f64);
+        let offset = (30.0, -150.0);
+        let (dark, flux) = (50.0, 0.01);
+        let inside = |x: f64, y: f64| {
+            let (dx, dy) = (x - width as f64 / 2.0 - offset.0, y - height as f64 / 2.0 - offset.1);
+            let (u, v) = (dx * theta.cos() + dy * theta.sin(), -dx * theta.sin() + dy * theta.cos());
+            (u / a).powi(2) + (v / b).powi(2) <= 1.0
+        };
+        // Counts each lit cell at 4×4 points.
+        let measure = |mask: &Mask| {
+            let lit: usize = mask.enumerate_pixels()
+                .filter(|(_, _, pixel)| pixel[0] > 0)
+                .map(|(x, y, _)| {
+                    (0 .. 16).filter(|i| {
+                        let step = CELL as f64 / 4.0;
+                        inside((x * CELL) as f64 + (i % 4) as f64 * step + step / 2.0,
+                               (y * CELL) as f64 + (i / 4) as f64 * step + step / 2.0)
+                    }).count()
+                })
+                .sum();
+            dark + flux * lit as f64 * (CELL * CELL) as f64 / 16.0
+        };
+
+        let mut search = ApertureSearch::new((width, height));
+        let mut masks = 0;
+        while let Some((mask, probe)) = search.next_mask() {
+            search.record(&probe, measure(&mask));
+            masks += 1;
+        }
+        assert!(masks < 400, "{} masks", masks);
+        assert_eq!(search.edge().len(), RAYS);
+
+        let fit = search.fit().unwrap();
+        assert!((fit.center.0 - offset.0).abs() < 4.0 && (fit.center.1 - offset.1).abs() < 4.0,
+                "{:?}", fit);
+        assert!(b < fit.radius && fit.radius < a, "{:?}", fit);
+        let (cxx, cyy, cxy) = fit.ellipse.unwrap();
+        let axes = ellipse_axes(cxx, cyy, cxy).unwrap();
+        assert!((axes.a - a).abs() < 6.0 && (axes.b - b).abs() < 6.0, "{:?}", axes);
+        assert!((axes.theta - theta).abs() < 0.05, "{:?}", axes);
+    }
+}
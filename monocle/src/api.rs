@@ -1,7 +1,14 @@
 use serde_derive::{Deserialize, Serialize};
 use crate::scanline::{Frame, ScanLine};
-use crate::gpio::Reading;
-use crate::quantity::{Altitude, Azimuth, RightAscension, Declination, PixelDistance};
+use crate::gpio::{Reading, WindowConfig};
+use crate::lcd::FrameInfo;
+use crate::display::{ConnectorInfo, DisplayConfig};
+use crate::edid::DetailedTiming;
+use crate::gamma::ResponseCurve;
+use crate::latency::Latency;
+use crate::profile::{ApertureCalibration, DarkRate, FlatField, Profile};
+use crate::quantity::{Altitude, Azimuth, RightAscension, Declination, PixelDistance,
+                      Latitude, RotationAngle};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SerializableRecord {
@@ -13,16 +20,94 @@ pub struct SerializableRecord {
     pub line: Option<u32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MaskSeq {
     ScanningBox,
-    // TODO: add more stuff
+    /// 2D Sylvester-Hadamard basis on an `n × n` grid (`n` a power of two).
+    Hadamard(usize),
+    /// Four-step phase-shifted sinusoids on an `n × n` grid.
+    Fourier(usize),
+    /// `count` Bernoulli masks on a `resolution × resolution` grid.
+    Random { resolution: usize, count: usize, seed: u64 },
+    /// Quadtree search that only refines regions measuring at least
+    /// `threshold`, displaying at most `budget` masks.
+    AdaptiveQuadtree { resolution: usize, budget: usize, threshold: f64 },
+    /// Haar wavelet tree search that only refines below coefficients of
+    /// magnitude at least `threshold`, displaying at most `budget` masks.
+    AdaptiveHaar { resolution: usize, budget: usize, threshold: f64 },
+    /// The panel held black for `frames` frames, to measure the dark rate.
+    Dark { frames: usize },
 }
 
-
+/// Everything needed to reconstruct an image from one mask sequence.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct Capture {
+    pub masks: MaskSeq,
+    pub pulses: Vec<((Frame, ScanLine), Reading)>,
+    /// Index of the mask each frame's windows measure, or `None` for frames
+    /// that are discarded (e.g. blank ones). Empty means frame `i` measures
+    /// mask `i`, as in captures from before page flips were tracked.
+    #[serde(default)]
+    pub frames: Vec<Option<usize>>,
+    /// Each mask was followed by its inverse, so that frame measurements come
+    /// in pattern/inverse pairs.
+    #[serde(default)]
+    pub differential: bool,
+    /// Sum of each pattern/inverse pair, i.e. the total flux over time; tracks
+    /// sky transparency and detector drift in differential captures.
+    #[serde(default)]
+    pub monitor: Vec<Option<f64>>,
+    /// Angle each frame's mask was rotated by to follow field rotation, in
+    /// radians; empty if the capture wasn't derotated.
+    #[serde(default)]
+    pub angles: Vec<RotationAngle>,
+    /// Every page flip during the capture; windows are tagged with an index
+    /// into this, and `frames` says which mask each flip showed.
+    #[serde(default)]
+    pub flips: Vec<FrameInfo>,
+    /// Display timing, to convert the scanline of each window to a time
+    /// within its frame.
+    #[serde(default)]
+    pub timing: Option<DetailedTiming>,
+    /// Panel response delay; with `timing`, windows are attributed to the
+    /// flip whose content they saw rather than the one they followed.
+    #[serde(default)]
+    pub latency: Option<Latency>,
+    /// Version of the calibration profile in effect on the Pi.
+    #[serde(default)]
+    pub profile: Option<u32>,
+}
+
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TakePictureReq {
     pub masks: Vec<MaskSeq>,
+    /// Show every mask followed by its inverse; ignored by adaptive sequences.
+    #[serde(default)]
+    pub differential: bool,
+    /// Site latitude in degrees. If given, masks are rotated to follow the
+    /// field rotation of the alt-az mount.
+    #[serde(default)]
+    pub derotate: Option<Latitude>,
+    /// Show masks again if their frame overran a vblank, so each gets a
+    /// clean frame; adaptive sequences only discard them.
+    #[serde(default)]
+    pub requeue_dropped: bool,
+    /// Draw fixed sequences into a pool of framebuffers up front and only
+    /// page-flip while capturing; ignored when derotating.
+    #[serde(default)]
+    pub preload: bool,
+    /// The scene is a uniform source (twilight sky or a diffuser): save the
+    /// reconstruction of the first sequence as the flat field.
+    #[serde(default)]
+    pub flat_field: bool,
+    /// Length and timing of the counting windows.
+    #[serde(default)]
+    pub window: WindowConfig,
+    /// Seconds to hold the panel black after `masks`, to measure the dark
+    /// rate. The server turns this into frames of the mode it displays.
+    #[serde(default)]
+    pub dark: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -46,7 +131,26 @@ pub struct CommandReq {
 pub enum CalibrateReq {
     Flicker,
     Latency,
+    /// Finds the aperture's centre and radius on the panel.
+    Aperture,
+    /// Measures the transmission of each channel against the byte written.
+    Response,
+    /// Offset from the screen centre and radius, in pixels. These used to be
+    /// framebuffer bytes horizontally, so horizontal offsets from older notes
+    /// are three times too large, and their circles were a third as wide.
     Cutoff(i32, i32, PixelDistance),
+    /// Offset and ellipse coefficients `cxx`, `cyy`, `cxy`, in pixels.
+    EllipticalCutoff(i32, i32, f64, f64, f64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ProfileReq {
+    /// A version of the calibration profile, or the latest.
+    Fetch(Option<u32>),
+    /// Saves a new version with every calibration the given profile has.
+    Update(Profile),
+    /// Saves a new version with the calibrations of an older one.
+    Rollback(u32),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,6 +159,11 @@ pub enum Request {
     GoTo(GoToReq),
     Command(CommandReq),
     Calibrate(CalibrateReq),
+    Profile(ProfileReq),
+    /// Lists connectors and modes.
+    Displays,
+    /// Switches to another connector or mode, if one matches.
+    ConfigureDisplay(DisplayConfig),
     Reboot,
     Reset,
     Close,
@@ -62,7 +171,15 @@ pub enum Request {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TakePictureResp {
-    pub pulses: Vec<Vec<((Frame, ScanLine), Reading)>>,
+    pub captures: Vec<Capture>,
+    /// Gains saved from the first capture, if it was a flat field.
+    #[serde(default)]
+    pub flat_field: Option<FlatField>,
+    /// Dark rate saved from a `MaskSeq::Dark` capture.
+    #[serde(default)]
+    pub dark: Option<DarkRate>,
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,7 +194,33 @@ pub struct CommandResp {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CalibrateResp {
+pub enum CalibrateResp {
+    /// The calibration runs in the background.
+    Started,
+    Done,
+    Latency(Latency),
+    Aperture(ApertureCalibration),
+    Response(Vec<ResponseCurve>),
+    Failed(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisplaysResp {
+    /// Configuration in effect after the request.
+    pub current: DisplayConfig,
+    pub connectors: Vec<ConnectorInfo>,
+    /// Why the card couldn't be probed or the requested configuration was
+    /// rejected.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProfileResp {
+    /// The version fetched or saved.
+    pub profile: Option<Profile>,
+    /// Every version stored on the Pi.
+    pub versions: Vec<u32>,
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,6 +229,8 @@ pub enum Response {
     GoTo(GoToResp),
     Command(CommandResp),
     Calibrate(CalibrateResp),
+    Displays(DisplaysResp),
+    Profile(ProfileResp),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
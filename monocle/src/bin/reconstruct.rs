@@ -0,0 +1,5 @@
+use monocle::reconstruct;
+
+fn main() {
+    reconstruct::main();
+}
@@ -1,31 +1,70 @@
 use crate::quantity::PixelDistance;
-use crate::scanline::{Frame, ScanLine, ScanLineMem, Scaler};
-use crate::gpio::{GPIO, Reading};
-use std::io::Write;
-use std::sync::Arc;
-use std::sync::atomic::{Ordering, AtomicU32};
+use crate::scanline::Frame;
+use crate::gpio::WindowConfig;
+use crate::gamma::ResponseCurve;
+use crate::latency::Latency;
+use crate::profile::ApertureCalibration;
+use std::error::Error;
+use log::*;
 
-pub fn circular_cutoff(x: i32, y: i32, radius: PixelDistance) {
+pub fn circular_cutoff(x: i32, y: i32, radius: PixelDistance) -> Result<(), Box<dyn Error>> {
     use crate::mask::*;
     use image::Luma;
-    use imageproc::geometric_transformations::translate;
 
     let mut call_number: u32 = 0;
-    let mut mask = Mask::from_pixel(4320, 2560, Luma([255]));
-    apply_circular_cutoff(&mut mask, radius);
-    let shifted = translate(&mask, (x, y));
-    println!("DEBUG: shifted size = {}", shifted.as_raw().len());
-    crate::lcd::run(|dm| {
-        println!("DEBUG: dm size = {}", dm.as_mut().len());
-        dm.as_mut().copy_from_slice(shifted.as_raw());
+    let geometry = crate::display::DisplayGeometry::new(&crate::lcd::config());
+    let pipeline = Pipeline {
+        geometry,
+        addressing: crate::display::Addressing::Pixel,
+        origin: (0.0, 0.0),
+        size: (geometry.width as f64, geometry.height as f64),
+        aperture: Aperture::Circular(radius),
+        translation: (x as f64, y as f64),
+        ..Pipeline::default()
+    };
+    let white = Mask::from_pixel(1, 1, Luma([255]));
+    let mut buffers = Framebuffers::default();
+    crate::lcd::run(|dm, info| {
+        buffers.render(info.rendering, dm.as_mut(), &pipeline, (), || &white);
         call_number += 1;
         call_number >= 200
-    }).unwrap();
+    })
 }
 
-pub fn flicker() {
+/// Like [`circular_cutoff`], for the ellipse `cxx x² + cyy y² + cxy xy = 1`
+/// around `(x, y)` from the screen center.
+pub fn elliptical_cutoff(x: i32, y: i32, cxx: f64, cyy: f64, cxy: f64)
+                         -> Result<(), Box<dyn Error>>
+{
+    use crate::mask::*;
+    use image::Luma;
+
+    let axes = crate::sep::aperture::ellipse_axes(cxx, cyy, cxy).ok_or_else(|| {
+        format!("({}, {}, {}) doesn't describe an ellipse", cxx, cyy, cxy)
+    })?;
     let mut call_number: u32 = 0;
-    crate::lcd::run(|dm| {
+    let geometry = crate::display::DisplayGeometry::new(&crate::lcd::config());
+    let pipeline = Pipeline {
+        geometry,
+        addressing: crate::display::Addressing::Pixel,
+        origin: (0.0, 0.0),
+        size: (geometry.width as f64, geometry.height as f64),
+        aperture: Aperture::from(axes),
+        translation: (x as f64, y as f64),
+        ..Pipeline::default()
+    };
+    let white = Mask::from_pixel(1, 1, Luma([255]));
+    let mut buffers = Framebuffers::default();
+    crate::lcd::run(|dm, info| {
+        buffers.render(info.rendering, dm.as_mut(), &pipeline, (), || &white);
+        call_number += 1;
+        call_number >= 200
+    })
+}
+
+pub fn flicker() -> Result<(), Box<dyn Error>> {
+    let mut call_number: u32 = 0;
+    crate::lcd::run(|dm, _| {
         dm.as_mut().fill(if (call_number % 10) > 5 {
             0u8
         } else {
@@ -33,63 +72,173 @@ pub fn flicker() {
         });
         call_number += 1;
         call_number >= 300
-    }).unwrap();
+    })
 }
 
-pub fn latency() -> u32 {
-    let frame_counter = Arc::new(AtomicU32::new(0));
-    let frame_counter_copy = frame_counter.clone();
-
-    let handle = std::thread::spawn(move || {
-        let slm = ScanLineMem::new().unwrap();
-        let gpio = GPIO::new().unwrap();
-        let mut photon_counts: Vec<((Frame, ScanLine), Reading)> = Vec::new();
-        photon_counts.reserve(40000);
-        for _ in 0 .. 20000 {
-            let scanline = slm.read_scanline(Scaler::Scaler0);
-            let frame = frame_counter_copy.load(Ordering::SeqCst);
-            if let Some(pulses) = gpio.record_window() {
-                photon_counts.push(((frame, scanline), pulses));
+/// Frames the panel is held black or white for, to settle between steps.
+const LATENCY_STEP_FRAMES: u32 = 12;
+const LATENCY_STEPS: u32 = 10;
+
+/// Flashes the whole panel between black and white while counting photons,
+/// fits the response to each step and stores it in the calibration profile.
+pub fn latency() -> Result<Latency, Box<dyn Error>> {
+    // Whether each buffer is white, and the flips that changed the level.
+    let mut contents = [false; 2];
+    let mut previous = false;
+    let mut steps: Vec<(Frame, bool)> = Vec::new();
+    let mut late: Vec<Frame> = Vec::new();
+    let mut flips: Frame = 0;
+    let frames = ((LATENCY_STEPS + 1) * LATENCY_STEP_FRAMES) as usize;
+    let recorded = crate::capture::count_while(WindowConfig::default(), frames, |counter| {
+        crate::lcd::run(|dm, info| {
+            counter.flipped(flips);
+            if info.missed > 0 {
+                late.push(flips);
             }
-        }
-        return photon_counts;
+            let shown = contents[info.displayed];
+            if shown != previous {
+                steps.push((flips, shown));
+                previous = shown;
+            }
+            if flips >= (LATENCY_STEPS + 1) * LATENCY_STEP_FRAMES {
+                return true;
+            }
+            let white = (flips / LATENCY_STEP_FRAMES) % 2 == 1;
+            dm.as_mut().fill(if white { 255 } else { 0 });
+            contents[info.rendering] = white;
+            flips += 1;
+            false
+        })
     });
+    let (result, pulses) = recorded?;
+    result?;
 
-    let mut call_number: u32 = 0;
-    let slm = crate::scanline::ScanLineMem::new().unwrap();
-    let mut start_scanline = None;
-    crate::lcd::run(|dm| {
-        frame_counter.fetch_add(1, Ordering::SeqCst);
-        // for pixel in dm.as_mut().iter_mut() {
-        //     *pixel = if call_number % 3 == 0 {
-        //         0u8
-        //     } else if call_number % 3 == 1 {
-        //         127u8
-        //     } else if call_number % 3 == 2 {
-        //         255u8
-        //     } else {
-        //         panic!("impossible")
-        //     };
-        // }
-        if call_number == 20 {
-            start_scanline = Some((frame_counter.load(Ordering::SeqCst),
-                                   slm.read_scanline(Scaler::Scaler0)));
-        }
-        dm.as_mut().fill(if call_number < 20 {
-            0u8
-        } else {
-            255u8
-        });
-        call_number += 1;
-        call_number >= 300
-    }).unwrap();
+    // A missed vblank shifts everything after it by a frame.
+    steps.retain(|(flip, _)| {
+        !late.iter().any(|l| *l + 1 >= *flip && *l <= flip + LATENCY_STEP_FRAMES)
+    });
+    let timing = crate::lcd::timing().ok_or("Display timing is unknown")?;
+    let latency = crate::latency::analyze(&pulses, &steps, LATENCY_STEP_FRAMES, &timing)
+        .ok_or("Couldn't fit the step response")?;
+    info!("Measured {:?}", latency);
+
+    crate::profile::Store::pi().update(|profile| {
+        profile.latency = Some(crate::profile::Calibrated::now(latency));
+    })?;
+    Ok(latency)
+}
 
-    let photon_counts = handle.join();
+/// Frames each level of the response sweep is shown for; windows of the
+/// first are discarded while the panel settles.
+const RESPONSE_STEP_FRAMES: u32 = 4;
+
+/// Sweeps each channel of the whole panel through [`sweep_levels`] while
+/// counting photons, fits its response and stores the curves in the
+/// calibration profile.
+///
+/// [`sweep_levels`]: crate::gamma::sweep_levels
+pub fn response() -> Result<Vec<ResponseCurve>, Box<dyn Error>> {
+    let geometry = crate::display::DisplayGeometry::new(&crate::lcd::config());
+    // Byte lit in every pixel and its level, with the panel black at both
+    // ends.
+    let mut steps: Vec<(usize, u8)> = vec![(0, 0)];
+    for byte in 0 .. 3 {
+        steps.extend(crate::gamma::sweep_levels().into_iter().map(|level| (byte, level)));
+    }
+    steps.push((0, 0));
+
+    // Step and repetition drawn in each buffer, and the step whose level
+    // each flip's windows measure.
+    let mut contents: [Option<(usize, u32)>; 2] = [None, None];
+    let mut measures: Vec<Option<usize>> = Vec::new();
+    let frames = steps.len() * RESPONSE_STEP_FRAMES as usize;
+    let recorded = crate::capture::count_while(WindowConfig::default(), frames, |counter| {
+        crate::lcd::run(|dm, info| {
+            let flip = measures.len() as Frame;
+            counter.flipped(flip);
+            measures.push(contents[info.displayed]
+                .filter(|(_, repetition)| *repetition > 0)
+                .map(|(step, _)| step));
+            let step = (flip / RESPONSE_STEP_FRAMES) as usize;
+            if step >= steps.len() {
+                return true;
+            }
+            let (byte, level) = steps[step];
+            for pixel in dm.as_mut().chunks_exact_mut(3) {
+                pixel.fill(0);
+                pixel[byte] = level;
+            }
+            contents[info.rendering] = Some((step, flip % RESPONSE_STEP_FRAMES));
+            false
+        })
+    });
+    let (result, pulses) = recorded?;
+    result?;
 
-    let mut file = std::fs::File::create("/calibration.txt").unwrap();
-    for tuple in photon_counts {
-        file.write_all(format!("{:?}\n", tuple).as_bytes()).unwrap();
+    let mean = |step: usize| {
+        crate::reconstruct::mean_rate(pulses.iter()
+            .filter(|((frame, _), _)| measures.get(*frame as usize) == Some(&Some(step)))
+            .map(|(_, reading)| reading))
+    };
+    let darks: Vec<f64> = [0, steps.len() - 1].iter().filter_map(|step| mean(*step)).collect();
+    if darks.is_empty() {
+        return Err("No windows were recorded with the panel black".into());
     }
-    file.write_all(format!("{:?}\n", start_scanline.unwrap()).as_bytes()).unwrap();
-    0
+    let dark = darks.iter().sum::<f64>() / darks.len() as f64;
+
+    let mut curves = Vec::new();
+    for byte in 0 .. 3 {
+        let counts: Vec<(u8, f64)> = steps.iter().enumerate()
+            .filter(|(_, (b, level))| *b == byte && *level > 0)
+            .filter_map(|(step, (_, level))| Some((*level, mean(step)?)))
+            .collect();
+        let channel = geometry.bytes[byte];
+        let curve = ResponseCurve::fit(channel, dark, &counts)
+            .ok_or_else(|| format!("{:?} subpixels transmitted nothing above dark", channel))?;
+        info!("{:?} response has gamma {:.2}", channel, curve.gamma);
+        curves.push(curve);
+    }
+
+    crate::profile::Store::pi().update(|profile| {
+        profile.response = Some(crate::profile::Calibrated::now(curves.clone()));
+    })?;
+    Ok(curves)
+}
+
+/// Finds the telescope's image on the panel with an
+/// [`ApertureSearch`](crate::aperture::ApertureSearch) and stores it in the
+/// calibration profile.
+pub fn aperture() -> Result<ApertureCalibration, Box<dyn Error>> {
+    use crate::adaptive::Sequencer;
+    use crate::aperture::ApertureSearch;
+
+    let geometry = crate::display::DisplayGeometry::new(&crate::lcd::config());
+    let mut search = ApertureSearch::new((geometry.width, geometry.height));
+    let (width, height) = search.size();
+    let pipeline = crate::mask::Pipeline {
+        geometry,
+        addressing: crate::display::Addressing::Pixel,
+        origin: (0.0, 0.0),
+        size: (width as f64, height as f64),
+        ..crate::mask::Pipeline::default()
+    };
+    let settings = crate::capture::Settings {
+        differential: false,
+        derotate: None,
+        requeue_dropped: false,
+        preload: false,
+        window: WindowConfig::default(),
+    };
+    crate::capture::display_sequence(pipeline, &settings, &mut search, 0)?;
+    // The search stops early if nothing was lit.
+    if search.next_mask().is_some() {
+        return Err("Aperture search didn't finish".into());
+    }
+    let aperture = search.fit().ok_or("No aperture edge was found")?;
+    info!("Found aperture {:?} from {} edge points", aperture, search.edge().len());
+
+    crate::profile::Store::pi().update(|profile| {
+        profile.aperture = Some(crate::profile::Calibrated::now(aperture));
+    })?;
+    Ok(aperture)
 }
@@ -1,111 +1,767 @@
+use crate::adaptive::{Adaptive, HaarSearch, Options, QuadtreeSearch, Sequencer};
+use crate::api::{Capture, MaskSeq};
+use crate::display::Addressing;
+use crate::mask::{Aperture, Framebuffers, Mask, Pipeline, invert_mask};
+use crate::pantilt::Connection;
+use crate::profile::{ApertureCalibration, Profile};
+use crate::quantity::{Latitude, RotationAngle};
+use crate::reconstruct::{measurements, pairs};
 use crate::rotation::FieldRotation;
 use crate::scanline::{Frame, ScanLine, ScanLineMem, Scaler};
-use crate::gpio::{GPIO, Reading};
-use std::sync::{Arc, Barrier, atomic::{AtomicBool, AtomicU32, Ordering}};
+use crate::gpio::{GPIO, Reading, WindowConfig, WindowTiming};
+use crate::lcd::{FrameInfo, MAX_PRELOADED, OutOfBuffers};
+use std::sync::{Arc, Barrier, Mutex, MutexGuard, atomic::{AtomicBool, AtomicU32, Ordering}};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::thread::JoinHandle;
+use std::time::Duration;
 use log::*;
-// center = (0, 210), radius = 750
 
 pub const DIVIDER: u32 = 6;
 
-pub fn scanbox() -> Vec<((Frame, ScanLine), Reading)> {
-    let barrier = Arc::new(Barrier::new(2));
-    let barrier_copy = barrier.clone();
+/// Side length of the region masks are drawn in, in subpixels horizontally
+/// and rows vertically; a third as wide as it is tall on the glass.
+pub const SCAN_SIZE: u32 = 1500;
 
-    let frame_counter = Arc::new(AtomicU32::new(0));
-    let frame_counter_copy = frame_counter.clone();
+/// Upper left corner of the mask region, in pixels.
+pub const SCAN_ORIGIN: (u32, u32) = (470, 740);
 
-    let kill_channel = Arc::new(AtomicBool::new(false));
-    let kill_channel_copy = kill_channel.clone();
+pub(crate) type Pulse = ((Frame, ScanLine), Reading);
+
+// Frames shown before an adaptive mask's windows are trusted, covering the
+// LCD response, and frames that are then averaged.
+const SETTLE_FRAMES: u32 = 1;
+const MEASURE_FRAMES: u32 = 2;
 
-    let handle = std::thread::spawn(move || {
+// Windows are tagged with the frame they start in. Ones longer than a frame
+// over this often run on into the next frame and count its light too.
+const MIN_WINDOWS_PER_FRAME: u32 = 10;
+
+// Counts photons over windows of `window` on a separate thread until
+// `kill_channel` is set, tagging each window with the current frame, i.e. the
+// index of the last page flip, and the scanline when it started. Room is
+// reserved up front for the windows of `frames` frames.
+fn spawn_counter(
+    barrier: Arc<Barrier>,
+    frame_counter: Arc<AtomicU32>,
+    kill_channel: Arc<AtomicBool>,
+    photon_counts: Arc<Mutex<Vec<Pulse>>>,
+    window: WindowConfig,
+    frames: usize,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
         barrier.wait();
         let slm = ScanLineMem::new().unwrap();
-        let gpio = GPIO::new().unwrap();
-        let mut photon_counts: Vec<((Frame, ScanLine), Reading)> = Vec::new();
-        // let start_frame = frame_counter_copy.load(Ordering::SeqCst);
-        photon_counts.reserve(50_000_000);
-        while !kill_channel_copy.load(Ordering::SeqCst) {
-            let scanline = slm.read_scanline(Scaler::Scaler0);
-            let frame = frame_counter_copy.load(Ordering::SeqCst);
-            if let Some(pulses) = gpio.record_window() {
-                photon_counts.push(((frame, scanline), pulses));
+        let mut gpio = GPIO::new().unwrap();
+        if let Err(e) = gpio.set_window(window) {
+            warn!("Counting over the default windows instead of {:?}: {}", window, e);
+        }
+        // The display is up by now, so its timing is known. Growing the
+        // record later would hold the lock while it is copied.
+        let frame = crate::lcd::timing().map_or(Duration::ZERO, |timing| timing.frame_duration());
+        let shortest = window.length().max(Duration::from_micros(1));
+        let windows = frames * (frame.as_nanos() / shortest.as_nanos()) as usize;
+        if let Err(e) = photon_counts.lock().unwrap().try_reserve(windows) {
+            warn!("Couldn't reserve room for {} windows: {}", windows, e);
+        }
+        if window.length() * MIN_WINDOWS_PER_FRAME > frame && frame > Duration::ZERO {
+            warn!("{:?} windows are over 1/{} of a {:?} frame, so many straddle page flips",
+                  window.length(), MIN_WINDOWS_PER_FRAME, frame);
+        }
+        if window.timing == WindowTiming::Continuous {
+            let length = window.length().as_nanos() as u64;
+            let position = |slm: &ScanLineMem| {
+                (frame_counter.load(Ordering::SeqCst), slm.read_scanline(Scaler::Scaler0))
+            };
+            // Windows abut: each one ends at the sample the next starts at.
+            let mut start = (position(&slm), gpio.sample());
+            while !kill_channel.load(Ordering::SeqCst) {
+                let sample = gpio.sample();
+                if sample.time - start.1.time >= length {
+                    let pulses = start.1.until(&sample);
+                    photon_counts.lock().unwrap().push((start.0, pulses));
+                    start = (position(&slm), sample);
+                }
+            }
+        } else {
+            while !kill_channel.load(Ordering::SeqCst) {
+                let scanline = slm.read_scanline(Scaler::Scaler0);
+                let frame = frame_counter.load(Ordering::SeqCst);
+                let pulses = gpio.record_window();
+                photon_counts.lock().unwrap().push(((frame, scanline), pulses));
+                // trace!("Recorded photon window in scanline: {:?}", scanline);
             }
-            // trace!("Recorded photon window in scanline: {:?}", scanline);
         }
         info!("Finished photon counting");
-        photon_counts
-    });
+    })
+}
 
-    let mut counter = 0;
-    crate::lcd::run(|dm| {
-        if counter % 50 == 0 {
-            info!("Reached frame {}", counter);
+/// The counter thread as seen from the render loop.
+pub(crate) struct Counter {
+    barrier: Arc<Barrier>,
+    started: Cell<bool>,
+    frame_counter: Arc<AtomicU32>,
+    kill_channel: Arc<AtomicBool>,
+    photon_counts: Arc<Mutex<Vec<Pulse>>>,
+    thread: JoinHandle<()>,
+}
+
+impl Counter {
+    /// Tags windows from here on with `flip`, the index of the page flip
+    /// that just happened. Counting starts at the first one.
+    pub fn flipped(&self, flip: Frame) {
+        if !self.started.replace(true) {
+            self.barrier.wait();
         }
-        if counter == 0 {
-            barrier_copy.wait();
+        self.frame_counter.store(flip, Ordering::SeqCst);
+    }
+
+    /// Windows recorded so far, in the order of their tags.
+    pub fn pulses(&self) -> MutexGuard<'_, Vec<Pulse>> {
+        self.photon_counts.lock().unwrap()
+    }
+
+    /// Whether the counting thread has exited, so no more windows will come.
+    pub fn stopped(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+/// Counts photons over windows of `window` while `display` drives the panel
+/// for about `frames` frames, reporting each flip to the [`Counter`], and
+/// returns its result with the windows recorded, or an error if the counting
+/// thread panicked.
+pub(crate) fn count_while<T>(
+    window: WindowConfig,
+    frames: usize,
+    display: impl FnOnce(&Counter) -> T,
+) -> Result<(T, Vec<Pulse>), Box<dyn Error>> {
+    let barrier = Arc::new(Barrier::new(2));
+    let frame_counter = Arc::new(AtomicU32::new(0));
+    let kill_channel = Arc::new(AtomicBool::new(false));
+    let photon_counts = Arc::new(Mutex::new(Vec::new()));
+    let thread = spawn_counter(barrier.clone(), frame_counter.clone(), kill_channel.clone(),
+                               photon_counts.clone(), window, frames);
+    let counter = Counter {
+        barrier, started: Cell::new(false), frame_counter, kill_channel, photon_counts, thread,
+    };
+    let result = display(&counter);
+    // Release the thread if the display failed before its first flip.
+    if !counter.started.get() {
+        counter.barrier.wait();
+    }
+    counter.kill_channel.store(true, Ordering::SeqCst);
+    let Counter { thread, photon_counts, .. } = counter;
+    if thread.join().is_err() {
+        return Err("The photon counting thread panicked".into());
+    }
+    let pulses = std::mem::take(&mut *photon_counts.lock().unwrap());
+    Ok((result, pulses))
+}
+
+// Mount position queries take tens of milliseconds over serial, so they run
+// on their own thread at this interval rather than in the render loop.
+const MOUNT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How masks are displayed, as set in `TakePictureReq`.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub differential: bool,
+    /// Site latitude in degrees, to derotate masks on an alt-az mount.
+    pub derotate: Option<Latitude>,
+    /// Show masks again if their frame overran a vblank.
+    pub requeue_dropped: bool,
+    /// Draw fixed sequences into a pool of framebuffers before displaying
+    /// them, so that each refresh only flips.
+    pub preload: bool,
+    pub window: WindowConfig,
+}
+
+/// Display timing over a capture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub flips: usize,
+    /// Flips that came after one or more skipped vblanks.
+    pub late: usize,
+    /// Total vblanks skipped.
+    pub missed: u64,
+}
+
+pub fn frame_stats(flips: &[FrameInfo]) -> FrameStats {
+    FrameStats {
+        flips: flips.len(),
+        late: flips.iter().filter(|info| info.missed > 0).count(),
+        missed: flips.iter().map(|info| info.missed as u64).sum(),
+    }
+}
+
+// When a flip arrives late, the frame before it stayed up for more than one
+// refresh, so its windows are discarded. Returns the mask that frame showed.
+fn discard_overrun(frames: &mut [Option<usize>], info: &FrameInfo) -> Option<usize> {
+    if info.missed == 0 {
+        return None;
+    }
+    frames.last_mut()?.take()
+}
+
+// What to draw for the upcoming flip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Show(usize),
+    Blank,
+    Done,
+}
+
+// Decides which display index of a fixed sequence each flip shows, recording
+// the flips and what was on screen during each.
+struct Schedule {
+    pending: std::ops::Range<usize>,
+    requeued: VecDeque<usize>,
+    requeue_dropped: bool,
+    frames: Vec<Option<usize>>,
+    flips: Vec<FrameInfo>,
+}
+
+impl Schedule {
+    fn new(pending: std::ops::Range<usize>, requeue_dropped: bool) -> Schedule {
+        Schedule {
+            pending,
+            requeued: VecDeque::new(),
+            requeue_dropped,
+            frames: Vec::new(),
+            flips: Vec::new(),
         }
-        assert_eq!(1500 % DIVIDER, 0);
-        let x = counter % (1500 / DIVIDER);
-        let y = counter / (1500 / DIVIDER);
-        if (x >= (1500 / DIVIDER)) || (y >= (1500 / DIVIDER)) {
-            return true;
+    }
+
+    // Records the flip `info`, which put `shown` on screen.
+    fn advance(&mut self, info: &FrameInfo, shown: Option<usize>) -> Step {
+        if let Some(overrun) = discard_overrun(&mut self.frames, info) {
+            if self.requeue_dropped {
+                self.requeued.push_back(overrun);
+            }
+        }
+        self.frames.push(shown);
+        self.flips.push(*info);
+        match self.requeued.pop_front().or_else(|| self.pending.next()) {
+            Some(index) => Step::Show(index),
+            // Wait until the last mask has been on screen for its whole frame.
+            None if shown.is_some() => Step::Blank,
+            None => Step::Done,
         }
-        let set_line = |buf: &mut [u8], start: u32, width: u32, value: u8| {
-            // if start > buf.len() as u32 {
-            //     panic!("DEBUG: start ({}) > buf.len() ({})", start, buf.len());
-            // }
-            // let foo = buf.split_at_mut(start as usize).1;
-            // if width > foo.len() as u32 {
-            //     panic!("DEBUG: width ({}) > foo.len() ({})", width, foo.len());
-            // }
-            buf
-                .split_at_mut(start as usize).1
-                .split_at_mut(width as usize).0
-                .fill(value);
+    }
+}
+
+// Mask at display index `index`, counting inverses in differential mode.
+fn displayed_mask(seq: &MaskSeq, index: usize, differential: bool) -> Mask {
+    if !differential {
+        return seq.pattern(index);
+    }
+    let pattern = seq.pattern(index / 2);
+    if index % 2 == 1 {
+        invert_mask(&pattern)
+    } else {
+        pattern
+    }
+}
+
+// Tracks field rotation from the mount position until finished.
+struct Derotator {
+    angle: Arc<Mutex<RotationAngle>>,
+    kill_channel: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Derotator {
+    // Connects to the mount before the capture starts; without one, masks
+    // are shown unrotated.
+    fn spawn(latitude: Latitude) -> Option<Derotator> {
+        let mut conn = match Connection::new() {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Couldn't connect to the mount, not derotating: {:?}", e);
+                return None;
+            },
         };
-        let set_block =
-            |buf: &mut [u8], x: u32, y: u32, width: u32, height: u32, value: u8| {
-                let upper_left = x + y * 4320;
-                for row in 0 .. height {
-                    set_line(buf, upper_left + row * 4320, width, value);
+        let angle = Arc::new(Mutex::new(0.0));
+        let angle_copy = angle.clone();
+        let kill_channel = Arc::new(AtomicBool::new(false));
+        let kill_copy = kill_channel.clone();
+        let handle = std::thread::spawn(move || {
+            let mut rotation = FieldRotation::new();
+            while !kill_copy.load(Ordering::SeqCst) {
+                // The mount reports decimal degrees.
+                match conn.get_az_alt() {
+                    Ok((az, alt)) => {
+                        *angle_copy.lock().unwrap() = rotation.update_angle(
+                            latitude.to_radians(), az.to_radians(), alt.to_radians());
+                    },
+                    Err(e) => warn!("Couldn't read mount position: {:?}", e),
                 }
-            };
-        // if !((x == 0) && (y == 0)) {
-        //     let (previous_x, previous_y) = if x == 0 {
-        //         ((4320 / DIVIDER) - 1, y - 1)
-        //     } else {
-        //         (x - 1, y)
-        //     };
-        //     set_block(dm.as_mut(), previous_x, previous_y, 0);
-        // }
-        dm.as_mut().fill(0);
-        set_block(dm.as_mut(),
-                  x * DIVIDER + 1410,
-                  y * DIVIDER + 740,
-                  DIVIDER,
-                  DIVIDER,
-                  255);
-        // for (i, pixel) in dm.as_mut().iter_mut().enumerate() {
-        //     let pixel_x = i % 4320;
-        //     let pixel_y = i / 4320;
-        //     *pixel = if (pixel_x / DIVIDER == x) && (pixel_y / DIVIDER == y) {
-        //         255u8
-        //     } else {
-        //         0u8
-        //     };
-        // }
-        counter += 1;
-        frame_counter.fetch_add(1, Ordering::SeqCst);
-        false
-        // counter >= (11_059_200 / (DIVIDER * DIVIDER))
-    }).unwrap();
-
-    std::thread::sleep(std::time::Duration::from_millis(100));
-
-    kill_channel.store(true, Ordering::SeqCst);
-
-    info!("Finished LCD display stuff");
-
-    handle.join().unwrap()
-    // Vec::new()
+                std::thread::sleep(MOUNT_POLL_INTERVAL);
+            }
+        });
+        Some(Derotator { angle, kill_channel, handle })
+    }
+
+    fn finish(self) {
+        self.kill_channel.store(true, Ordering::SeqCst);
+        if self.handle.join().is_err() {
+            warn!("Mount polling thread panicked");
+        }
+    }
+
+    // Points `pipeline` along the field, recording the angle used.
+    fn apply(&self, pipeline: &mut Pipeline, angles: &mut Vec<RotationAngle>) {
+        let angle = *self.angle.lock().unwrap();
+        angles.push(angle);
+        pipeline.rotation = angle;
+    }
+}
+
+// Shrinks the mask region to a whole number of subpixels across the stripes
+// and pixels along them per grid cell, so that every cell has the same area.
+// Once the aperture is calibrated, the region is centred on the telescope's
+// image and limited to it.
+fn pipeline(seq: &MaskSeq, profile: &Profile) -> Pipeline {
+    let resolution = seq.resolution() as u32;
+    let block = SCAN_SIZE / resolution;
+    assert!(block > 0, "Resolution {} exceeds the mask region", resolution);
+    let side = (block * resolution) as f64;
+    let geometry = crate::display::DisplayGeometry::new(&crate::lcd::config());
+    let (columns, rows) = geometry.resolution(Addressing::Subpixel);
+    let mut pipeline = Pipeline {
+        size: (side * geometry.width as f64 / columns as f64,
+               side * geometry.height as f64 / rows as f64),
+        response: profile.response(&geometry),
+        geometry,
+        ..Pipeline::default()
+    };
+    if let Some(aperture) = &profile.aperture {
+        let ApertureCalibration { center, radius, ellipse } = aperture.value;
+        // The calibration is about the screen center, the translation about
+        // the region's.
+        pipeline.translation = (
+            geometry.width as f64 / 2.0 + center.0 - (pipeline.origin.0 + pipeline.size.0 / 2.0),
+            geometry.height as f64 / 2.0 + center.1 - (pipeline.origin.1 + pipeline.size.1 / 2.0),
+        );
+        pipeline.aperture = ellipse
+            .and_then(|(cxx, cyy, cxy)| crate::sep::aperture::ellipse_axes(cxx, cyy, cxy))
+            .map_or(Aperture::Circular(radius), Aperture::from);
+    }
+    pipeline
+}
+
+/// Displays `seq` while counting photons. Fails if the display can't be
+/// driven, e.g. when the configured mode isn't offered.
+pub fn capture(seq: &MaskSeq, settings: &Settings) -> Result<Capture, Box<dyn Error>> {
+    match seq {
+        MaskSeq::AdaptiveQuadtree { .. } => {
+            let options = Options::from_sequence(seq).unwrap();
+            if settings.differential || settings.preload {
+                warn!("Ignoring differential and preload options for adaptive {:?}", seq);
+            }
+            capture_adaptive(seq, settings, QuadtreeSearch::new(&options))
+        },
+        MaskSeq::AdaptiveHaar { .. } => {
+            let options = Options::from_sequence(seq).unwrap();
+            if settings.differential || settings.preload {
+                warn!("Ignoring differential and preload options for adaptive {:?}", seq);
+            }
+            capture_adaptive(seq, settings, HaarSearch::new(&options))
+        },
+        MaskSeq::Dark { .. } if settings.differential => {
+            warn!("Ignoring differential option for {:?}", seq);
+            capture_static(seq, &Settings { differential: false, ..settings.clone() })
+        },
+        _ => capture_static(seq, settings),
+    }
+}
+
+// With `settings.differential`, each mask is followed by its inverse.
+fn capture_static(seq: &MaskSeq, settings: &Settings) -> Result<Capture, Box<dyn Error>> {
+    let differential = settings.differential;
+    let profile = crate::profile::current();
+    let derotator = settings.derotate.and_then(Derotator::spawn);
+    let mut angles = Vec::new();
+
+    let mut pipeline = pipeline(seq, &profile);
+    let repeats = if differential { 2 } else { 1 };
+    let length = seq.len() * repeats;
+    let mut schedule = Schedule::new(0 .. length, settings.requeue_dropped);
+
+    if settings.preload && derotator.is_some() {
+        warn!("Can't preload masks while derotating; drawing each frame instead");
+    }
+    let recorded = count_while(settings.window, length, |counter| {
+        let displayed = if settings.preload && derotator.is_none() {
+            // Masks are preloaded in batches that fit in memory, with buffer 0
+            // blank and buffer i + 1 holding display index `start + i`. Windows
+            // between batches are tagged with the last flip, which was blank.
+            // Batches shrink to what the GPU could allocate.
+            let mut batch = MAX_PRELOADED - 1;
+            let mut start = 0;
+            let mut displayed = Ok(());
+            while start < length {
+                let end = (start + batch).min(length);
+                schedule.pending = start .. end;
+                let result = crate::lcd::run_preloaded(end - start + 1, |buffer, dm| {
+                    if buffer == 0 {
+                        dm.as_mut().fill(0);
+                    } else {
+                        let mask = displayed_mask(seq, start + buffer - 1, differential);
+                        pipeline.render(&mask, dm.as_mut());
+                    }
+                }, |info| {
+                    if schedule.flips.len() % 50 == 0 {
+                        info!("Reached frame {}", schedule.flips.len());
+                    }
+                    counter.flipped(schedule.flips.len() as Frame);
+                    let shown = info.displayed.checked_sub(1).map(|i| start + i);
+                    match schedule.advance(info, shown) {
+                        Step::Show(index) => Some(index - start + 1),
+                        Step::Blank => Some(0),
+                        Step::Done => None,
+                    }
+                });
+                match result {
+                    Ok(()) => start = end,
+                    Err(e) => match e.downcast_ref::<OutOfBuffers>().copied() {
+                        Some(out) if out.allocated >= 2 => {
+                            warn!("{}; preloading {} masks at a time", out, out.allocated - 1);
+                            batch = out.allocated - 1;
+                        },
+                        _ => {
+                            displayed = Err(e);
+                            break;
+                        },
+                    },
+                }
+            }
+            displayed
+        } else {
+            // Index (counting inverses) of the mask drawn in each buffer.
+            let mut contents: [Option<usize>; 2] = [None, None];
+            let mut buffers = Framebuffers::default();
+            crate::lcd::run(|dm, info| {
+                if schedule.flips.len() % 50 == 0 {
+                    info!("Reached frame {}", schedule.flips.len());
+                }
+                // Windows from here on see what this flip put on screen.
+                counter.flipped(schedule.flips.len() as Frame);
+                match schedule.advance(info, contents[info.displayed]) {
+                    Step::Show(index) => {
+                        if let Some(derotator) = &derotator {
+                            derotator.apply(&mut pipeline, &mut angles);
+                        }
+                        buffers.render(info.rendering, dm.as_mut(), &pipeline, index,
+                                       || displayed_mask(seq, index, differential));
+                        contents[info.rendering] = Some(index);
+                    },
+                    Step::Blank => {
+                        buffers.blank(info.rendering, dm.as_mut());
+                        contents[info.rendering] = None;
+                    },
+                    Step::Done => return true,
+                }
+                false
+            })
+        };
+        // Let the counter catch up with the last flip.
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        displayed
+    });
+    let Schedule { frames, flips, .. } = schedule;
+    info!("Finished LCD display stuff: {:?}", frame_stats(&flips));
+    if let Some(derotator) = derotator {
+        derotator.finish();
+    }
+    let (displayed, pulses) = recorded?;
+    displayed?;
+    let mut capture = Capture {
+        masks: seq.clone(),
+        pulses,
+        frames,
+        differential,
+        monitor: Vec::new(),
+        angles,
+        flips,
+        timing: crate::lcd::timing(),
+        latency: profile.latency(),
+        profile: Some(profile.version),
+    };
+    if differential {
+        capture.monitor = pairs(&measurements(&capture)).1;
+    }
+    Ok(capture)
+}
+
+/// Windows and page flips recorded while masks were displayed.
+pub(crate) struct Recording {
+    pub pulses: Vec<Pulse>,
+    /// Index of the mask each flip's windows measure, counting from the
+    /// first one the sequencer returned.
+    pub frames: Vec<Option<usize>>,
+    pub flips: Vec<FrameInfo>,
+    pub angles: Vec<RotationAngle>,
+}
+
+// Count rate of the windows tagged with frames that measured mask `index`,
+// or `None` while windows tagged before frame `now` may still be counting.
+// The counter pushes windows in the order of their tags, so they are all in
+// once one tagged `now` or later is. These are exactly the windows
+// `reconstruct::measurements` averages for the mask. Windows before `cursor`
+// were consumed by earlier masks; it is advanced past those used here.
+fn feedback(
+    pulses: &[Pulse],
+    cursor: &mut usize,
+    frames: &[Option<usize>],
+    index: usize,
+    now: Frame,
+) -> Option<Option<f64>> {
+    let end = *cursor + pulses[*cursor ..].partition_point(|((frame, _), _)| *frame < now);
+    if end == pulses.len() {
+        return None;
+    }
+    let mean = crate::reconstruct::mean_rate(pulses[*cursor .. end].iter()
+        .filter(|((frame, _), _)| frames.get(*frame as usize) == Some(&Some(index)))
+        .map(|(_, reading)| reading));
+    *cursor = end;
+    Some(mean)
+}
+
+/// Shows each mask `search` asks for over `SETTLE_FRAMES + MEASURE_FRAMES`
+/// frames and feeds back the count rate of the measured ones that didn't
+/// overrun, showing it again if they all did. The next mask can only be drawn
+/// once that rate is in, so each mask also stays up for one frame after its
+/// measurement, `SETTLE_FRAMES + MEASURE_FRAMES + 1` in all. Windows are
+/// averaged with `reconstruct::mean_rate` so adaptive searches can be replayed
+/// offline from the capture. `masks` is about how many `search` asks for, or
+/// zero if that isn't known. Fails if the counter stops before a mask's
+/// windows are in.
+pub(crate) fn display_sequence<S: Sequencer>(
+    mut pipeline: Pipeline,
+    settings: &Settings,
+    search: &mut S,
+    masks: usize,
+) -> Result<Recording, Box<dyn Error>> {
+    let derotator = settings.derotate.and_then(Derotator::spawn);
+    let mut angles = Vec::new();
+
+    // Mask index and how many times in a row it had been drawn, per buffer.
+    let mut contents: [Option<(usize, u32)>; 2] = [None, None];
+    let mut buffers = Framebuffers::default();
+    let mut previous: Option<(usize, u32)> = None;
+    let mut frames: Vec<Option<usize>> = Vec::new();
+    let mut flips = Vec::new();
+    // Mask being drawn, its measurement handle, index and repetitions so far.
+    let mut current: Option<(Mask, S::Handle, usize, u32)> = None;
+    let mut cursor = 0;
+    let mut issued = 0;
+    // Mask whose windows were still missing when the counter stopped.
+    let mut lost = None;
+    let shows = masks * (SETTLE_FRAMES + MEASURE_FRAMES + 1) as usize;
+    let recorded = count_while(settings.window, shows, |counter| {
+        let displayed = crate::lcd::run(|dm, info| {
+            if flips.len() % 50 == 0 {
+                info!("Reached frame {}", flips.len());
+            }
+            counter.flipped(flips.len() as Frame);
+            discard_overrun(&mut frames, info);
+            let shown = contents[info.displayed];
+            frames.push(shown
+                .filter(|(_, repetition)| {
+                    (SETTLE_FRAMES .. SETTLE_FRAMES + MEASURE_FRAMES).contains(repetition)
+                })
+                .map(|(index, _)| index));
+            flips.push(*info);
+
+            // If the previous flip was the last measured showing of the current
+            // mask, feed it back once its windows are all in.
+            if previous.map(|(_, repetition)| repetition) == Some(SETTLE_FRAMES + MEASURE_FRAMES - 1) {
+                let (mask, handle, index, _) = current.take().unwrap();
+                let now = (flips.len() - 1) as Frame;
+                let mean = loop {
+                    // Checked first so the windows it pushed before exiting
+                    // are all seen below.
+                    let stopped = counter.stopped();
+                    let pulses = counter.pulses();
+                    if let Some(mean) = feedback(&pulses, &mut cursor, &frames, index, now) {
+                        break mean;
+                    }
+                    if stopped {
+                        lost = Some(index);
+                        return true;
+                    }
+                    drop(pulses);
+                    std::thread::yield_now();
+                };
+                match mean {
+                    Some(mean) => search.record(&handle, mean),
+                    // Every measured frame overran; rather than guess, start
+                    // showing the mask over again.
+                    None => {
+                        warn!("Mask {} wasn't measured, showing it again", index);
+                        current = Some((mask, handle, index, 0));
+                    },
+                }
+            }
+            previous = shown;
+
+            if current.is_none() {
+                match search.next_mask() {
+                    Some((mask, handle)) => {
+                        current = Some((mask, handle, issued, 0));
+                        issued += 1;
+                    },
+                    None => return true,
+                }
+            }
+            // Keep showing the mask until its measurement is in.
+            let (mask, _, index, repetitions) = current.as_mut().unwrap();
+            if let Some(derotator) = &derotator {
+                derotator.apply(&mut pipeline, &mut angles);
+            }
+            buffers.render(info.rendering, dm.as_mut(), &pipeline, *index, || &*mask);
+            contents[info.rendering] = Some((*index, *repetitions));
+            *repetitions += 1;
+            false
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        displayed
+    });
+    info!("Finished adaptive display: {:?}", frame_stats(&flips));
+    if let Some(derotator) = derotator {
+        derotator.finish();
+    }
+    let (displayed, pulses) = recorded?;
+    if let Some(index) = lost {
+        return Err(format!("Photon counting stopped before mask {} was measured", index).into());
+    }
+    displayed?;
+    Ok(Recording { pulses, frames, flips, angles })
+}
+
+fn capture_adaptive<A: Adaptive>(
+    seq: &MaskSeq,
+    settings: &Settings,
+    mut search: A,
+) -> Result<Capture, Box<dyn Error>> {
+    let profile = crate::profile::current();
+    let recording = display_sequence(pipeline(seq, &profile), settings, &mut search, seq.len())?;
+    Ok(Capture {
+        masks: seq.clone(),
+        pulses: recording.pulses,
+        frames: recording.frames,
+        differential: false,
+        monitor: Vec::new(),
+        angles: recording.angles,
+        flips: recording.flips,
+        timing: crate::lcd::timing(),
+        // Windows were fed back as tagged, so replay mustn't shift them.
+        latency: None,
+        profile: Some(profile.version),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flip(sequence: u32, missed: u32) -> FrameInfo {
+        FrameInfo {
+            sequence,
+            timestamp: Duration::from_micros(16_667 * sequence as u64),
+            displayed: (sequence % 2) as usize,
+            rendering: 1 - (sequence % 2) as usize,
+            missed,
+        }
+    }
+
+    #[test]
+    fn overrun_frames_are_discarded_and_counted() {
+        let flips = [flip(10, 0), flip(11, 0), flip(13, 1), flip(14, 0), flip(17, 2)];
+        let mut frames = Vec::new();
+        let mut dropped = Vec::new();
+        for (i, info) in flips.iter().enumerate() {
+            dropped.extend(discard_overrun(&mut frames, info));
+            frames.push(Some(i));
+        }
+        assert_eq!(frames, vec![Some(0), None, Some(2), None, Some(4)]);
+        assert_eq!(dropped, vec![1, 3]);
+        assert_eq!(frame_stats(&flips), FrameStats { flips: 5, late: 2, missed: 3 });
+    }
+
+    #[test]
+    fn schedule_requeues_overruns_and_ends_blank() {
+        let mut schedule = Schedule::new(0 .. 2, true);
+        assert_eq!(schedule.advance(&flip(0, 0), None), Step::Show(0));
+        assert_eq!(schedule.advance(&flip(1, 0), Some(0)), Step::Show(1));
+        // Mask 0 stayed up for two refreshes.
+        assert_eq!(schedule.advance(&flip(3, 1), Some(1)), Step::Show(0));
+        assert_eq!(schedule.advance(&flip(4, 0), Some(0)), Step::Blank);
+        assert_eq!(schedule.advance(&flip(5, 0), None), Step::Done);
+        assert_eq!(schedule.frames, vec![None, None, Some(1), Some(0), None]);
+        assert_eq!(schedule.flips.len(), 5);
+    }
+
+    #[test]
+    fn live_feedback_matches_offline_replay() {
+        let seq = MaskSeq::AdaptiveQuadtree { resolution: 16, budget: 60, threshold: 3e4 };
+        let options = Options::from_sequence(&seq).unwrap();
+        let mut scene = crate::reconstruct::Image::zeros((16, 16));
+        scene[(3, 5)] = 5e5;
+        scene[(12, 9)] = 3e5;
+        scene[(7, 7)] = 4e4;
+
+        // Four windows per frame, counting more the later they start, so
+        // that leaving any out changes the rate.
+        let window = |frame: Frame, rate: f64, i: u32| ((frame, i * 100), Reading {
+            overlight: false,
+            counter: (rate * 1e-4) as u64 + i as u64,
+            start: 0,
+            duration: 100_000,
+        });
+        let mut live = QuadtreeSearch::new(&options);
+        let (mut pulses, mut frames) = (Vec::new(), Vec::new());
+        let (mut cursor, mut index) = (0, 0);
+        while let Some((mask, handle)) = live.next_mask() {
+            let rate: f64 = mask.enumerate_pixels()
+                .map(|(x, y, p)| p.0[0] as f64 / 255.0 * scene[(y as usize, x as usize)])
+                .sum();
+            for repetition in 0 .. SETTLE_FRAMES + MEASURE_FRAMES {
+                let frame = frames.len() as Frame;
+                frames.push((repetition >= SETTLE_FRAMES).then_some(index));
+                pulses.extend((0 .. 3).map(|i| window(frame, rate, i)));
+            }
+            // The next flip comes while the last window is still counting.
+            let now = frames.len() as Frame;
+            frames.push(None);
+            assert_eq!(feedback(&pulses, &mut cursor, &frames, index, now), None);
+            pulses.push(window(now - 1, rate, 3));
+            assert_eq!(feedback(&pulses, &mut cursor, &frames, index, now), None);
+            pulses.push(window(now, 0.0, 0));
+            let mean = feedback(&pulses, &mut cursor, &frames, index, now).unwrap();
+            live.record(&handle, mean.unwrap());
+            index += 1;
+        }
+        assert!(index > 10 && index < 60, "{} masks", index);
+
+        let capture = Capture {
+            masks: seq,
+            pulses,
+            frames,
+            differential: false,
+            monitor: Vec::new(),
+            angles: Vec::new(),
+            flips: Vec::new(),
+            timing: None,
+            latency: None,
+            profile: None,
+        };
+        let values = measurements(&capture);
+        assert!(values[.. index].iter().all(Option::is_some));
+        assert_eq!(crate::adaptive::replay::<QuadtreeSearch>(&options, &values, 0.0),
+                   Some(live.reconstruct()));
+    }
 }
@@ -10,17 +10,177 @@ use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use scan_fmt::scan_fmt;
 use crate::api::*;
+use crate::gpio::{WindowConfig, WindowTiming};
 
 pub fn parse_command(string: &str) -> Option<Request> {
     // TODO: use scan_fmt for this parsing
     if string == "reset" {
         println!("\x1Bc\n");
         None
+    } else if let Some(rest) = string.strip_prefix("diff ") {
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    differential: true,
+                    ..req
+                }))
+            },
+            _ => None,
+        }
+    } else if let Some(args) = string.strip_prefix("derotate ") {
+        let (latitude, rest) = args.split_once(' ')?;
+        let latitude = latitude.parse::<f64>().ok()?;
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    derotate: Some(latitude),
+                    ..req
+                }))
+            },
+            _ => None,
+        }
+    } else if let Some(rest) = string.strip_prefix("requeue ") {
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    requeue_dropped: true,
+                    ..req
+                }))
+            },
+            _ => None,
+        }
+    } else if let Some(rest) = string.strip_prefix("preload ") {
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    preload: true,
+                    ..req
+                }))
+            },
+            _ => None,
+        }
+    } else if let Some(rest) = string.strip_prefix("flat ") {
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    flat_field: true,
+                    ..req
+                }))
+            },
+            _ => None,
+        }
+    } else if let Some(args) = string.strip_prefix("window ") {
+        let (micros, rest) = args.split_once(' ')?;
+        let micros = micros.parse::<u32>().ok()?;
+        let (timing, rest) = if let Some(rest) = rest.strip_prefix("timerfd ") {
+            (WindowTiming::TimerFd, rest)
+        } else if let Some(rest) = rest.strip_prefix("continuous ") {
+            (WindowTiming::Continuous, rest)
+        } else {
+            (WindowTiming::BusyWait, rest)
+        };
+        let window = WindowConfig { micros, timing };
+        if !window.is_valid() {
+            return None;
+        }
+        match parse_command(rest)? {
+            Request::TakePicture(req) => {
+                Some(Request::TakePicture(TakePictureReq {
+                    window,
+                    ..req
+                }))
+            },
+            _ => None,
+        }
     } else if string == "scanning_box" {
         Some(Request::TakePicture(TakePictureReq {
-            masks: vec![
-                MaskSeq::ScanningBox,
-            ],
+            masks: vec![MaskSeq::ScanningBox],
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("dark ") {
+        let seconds = scan_fmt!(args, "{f}", f64).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            dark: Some(seconds),
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("hadamard ") {
+        let n = scan_fmt!(args, "{d}", usize).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            masks: vec![MaskSeq::Hadamard(n)],
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("fourier ") {
+        let n = scan_fmt!(args, "{d}", usize).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            masks: vec![MaskSeq::Fourier(n)],
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("random ") {
+        let (resolution, count, seed) =
+            scan_fmt!(args, "{d} {d} {d}", usize, usize, u64).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            masks: vec![MaskSeq::Random { resolution, count, seed }],
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("quadtree ") {
+        let (resolution, budget, threshold) =
+            scan_fmt!(args, "{d} {d} {f}", usize, usize, f64).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            masks: vec![MaskSeq::AdaptiveQuadtree { resolution, budget, threshold }],
+            ..Default::default()
+        }))
+    } else if let Some(args) = string.strip_prefix("haar ") {
+        let (resolution, budget, threshold) =
+            scan_fmt!(args, "{d} {d} {f}", usize, usize, f64).ok()?;
+        Some(Request::TakePicture(TakePictureReq {
+            masks: vec![MaskSeq::AdaptiveHaar { resolution, budget, threshold }],
+            ..Default::default()
+        }))
+    } else if string == "profile" {
+        Some(Request::Profile(ProfileReq::Fetch(None)))
+    } else if let Some(args) = string.strip_prefix("profile ") {
+        let version = scan_fmt!(args, "{d}", u32).ok()?;
+        Some(Request::Profile(ProfileReq::Fetch(Some(version))))
+    } else if let Some(args) = string.strip_prefix("rollback ") {
+        let version = scan_fmt!(args, "{d}", u32).ok()?;
+        Some(Request::Profile(ProfileReq::Rollback(version)))
+    } else if let Some(args) = string.strip_prefix("set ") {
+        use crate::profile::*;
+        let mut update = Profile::default();
+        if let Some(args) = args.strip_prefix("dark ") {
+            let (mean, variance, windows) =
+                scan_fmt!(args, "{f} {f} {d}", f64, f64, usize).ok()?;
+            update.dark = Some(Calibrated::now(DarkRate { mean, variance, windows }));
+        } else if let Some(args) = args.strip_prefix("aperture ") {
+            let (x, y, radius) = scan_fmt!(args, "{f} {f} {f}", f64, f64, f64).ok()?;
+            update.aperture = Some(Calibrated::now(ApertureCalibration {
+                center: (x, y),
+                radius,
+                ellipse: None,
+            }));
+        } else if let Some(args) = args.strip_prefix("pins ") {
+            let pins: Vec<u8> = args.split_whitespace()
+                .map(|pin| pin.parse().ok())
+                .collect::<Option<_>>()?;
+            let (overlight, counter) = pins.split_first()?;
+            let pins = crate::gpio::PinMap {
+                overlight: *overlight,
+                counter: counter.try_into().ok()?,
+            };
+            pins.check().ok()?;
+            update.pins = Some(Calibrated::now(pins));
+        } else {
+            return None;
+        }
+        Some(Request::Profile(ProfileReq::Update(update)))
+    } else if string == "displays" {
+        Some(Request::Displays)
+    } else if let Some(spec) = string.strip_prefix("display ") {
+        Some(Request::ConfigureDisplay(spec.parse().ok()?))
+    } else if let Some(spec) = string.strip_prefix("display-unchecked ") {
+        Some(Request::ConfigureDisplay(crate::display::DisplayConfig {
+            check_edid: false,
+            ..spec.parse().ok()?
         }))
     } else if string == "reboot" {
         Some(Request::Reboot)
@@ -28,12 +188,27 @@ pub fn parse_command(string: &str) -> Option<Request> {
         Some(Request::Reset)
     } else if string == "latency" {
         Some(Request::Calibrate(CalibrateReq::Latency))
+    } else if string == "aperture" {
+        Some(Request::Calibrate(CalibrateReq::Aperture))
+    } else if string == "gamma" {
+        Some(Request::Calibrate(CalibrateReq::Response))
     } else if string == "flicker" {
         Some(Request::Calibrate(CalibrateReq::Flicker))
     } else if let Some(args) = string.strip_prefix("cutoff ") {
-        let (x, y, dist) = scan_fmt!(args, "{d} {d} {f}",
-                                     i32, i32, f64).ok()?;
+        let parsed = scan_fmt!(args, "{d} {d} {f}", i32, i32, f64);
+        if parsed.is_err() {
+            println!("Usage: cutoff X Y RADIUS, in pixels from the screen centre \
+                      (older notes give X in framebuffer bytes, three per pixel)");
+        }
+        let (x, y, dist) = parsed.ok()?;
         Some(Request::Calibrate(CalibrateReq::Cutoff(x, y, dist)))
+    } else if let Some(args) = string.strip_prefix("ellipse ") {
+        let parsed = scan_fmt!(args, "{d} {d} {f} {f} {f}", i32, i32, f64, f64, f64);
+        if parsed.is_err() {
+            println!("Usage: ellipse X Y CXX CYY CXY, in pixels from the screen centre");
+        }
+        let (x, y, cxx, cyy, cxy) = parsed.ok()?;
+        Some(Request::Calibrate(CalibrateReq::EllipticalCutoff(x, y, cxx, cyy, cxy)))
     } else if let Some(command_with_args) = string.strip_prefix("run ") {
         let mut iterator = command_with_args.split_whitespace();
         let command = iterator.next()?.to_string();
@@ -61,63 +236,125 @@ pub fn handle_receivable(receivable: &Receivable) -> bool {
             println!("\x1B[1mstderr:\x1B[0m\n{}", stderr);
             true
         }
-        Receivable::Response(Response::TakePicture(pulse_sets)) => {
-            use crate::scanline::{Frame, ScanLine};
-            use crate::gpio::Reading;
-            use float_ord::FloatOrd;
-            use exr::prelude::f16;
-
-            let resolution = 1500 / (crate::capture::DIVIDER as usize);
-
+        Receivable::Response(Response::TakePicture(resp)) => {
             let filename_prefix =
                 format!("/home/remy/compressive-output/{}",
                         chrono::Local::now().format("%Y-%m-%d %H:%M:%S"));
 
-            std::fs::write(format!("{}.cbor", filename_prefix),
-                           serde_cbor::to_vec(&(resolution, pulse_sets))
-                           .unwrap()).unwrap();
-
-            let readings: &Vec<((Frame, ScanLine), Reading)> =
-                &pulse_sets.pulses[0];
-            let mut frame_map: HashMap<Frame, (u32, u32)> = HashMap::new();
-            for ((frame, scanline), reading) in readings {
-                if !frame_map.contains_key(frame) {
-                    frame_map.insert(*frame, (0, 0));
+            for (i, capture) in resp.captures.iter().enumerate() {
+                let prefix = if resp.captures.len() == 1 {
+                    filename_prefix.clone()
+                } else {
+                    format!("{}-{}", filename_prefix, i)
+                };
+                crate::reconstruct::save(capture, format!("{}.cbor", prefix))
+                    .unwrap();
+                let (image, errors) = match crate::reconstruct::reconstruct_calibrated(
+                    capture, &Default::default(),
+                    &crate::reconstruct::capture_profile(capture)) {
+                    Ok(reconstruction) => reconstruction,
+                    Err(e) => {
+                        println!("Couldn't reconstruct {}.cbor: {}", prefix, e);
+                        continue;
+                    },
+                };
+                crate::reconstruct::write_exr(&image, format!("{}.exr", prefix))
+                    .unwrap();
+                if let Some(errors) = &errors {
+                    crate::reconstruct::write_exr(errors, format!("{}-error.exr", prefix))
+                        .unwrap();
+                }
+                println!("Minimum value: {}",
+                         image.fold(f64::INFINITY, |a, b| a.min(*b)));
+                println!("Maximum value: {}",
+                         image.fold(f64::NEG_INFINITY, |a, b| a.max(*b)));
+                let stats = crate::capture::frame_stats(&capture.flips);
+                if stats.late > 0 {
+                    println!("{} of {} frames overran, missing {} vblanks",
+                             stats.late, stats.flips, stats.missed);
                 }
-                frame_map.get_mut(frame).unwrap().0 += 1;
-                frame_map.get_mut(frame).unwrap().1 += reading.counter;
+                let totals: Vec<f64> = capture.monitor.iter().flatten().cloned().collect();
+                if !totals.is_empty() {
+                    let mean = totals.iter().sum::<f64>() / (totals.len() as f64);
+                    let spread = totals.iter()
+                        .fold(0.0, |m: f64, t| m.max((t - mean).abs()));
+                    println!("Total flux drifted by up to {:.1}% of {:.1}",
+                             100.0 * spread / mean, mean);
+                }
+                println!("Wrote to {}.exr", prefix);
+            }
+            if let Some(flat) = &resp.flat_field {
+                let gains: Vec<f64> = flat.gains.iter().cloned().filter(|g| *g > 0.0).collect();
+                println!("Saved {}² flat field, gains {:.3} to {:.3}", flat.resolution,
+                         gains.iter().cloned().fold(f64::INFINITY, f64::min),
+                         gains.iter().cloned().fold(f64::NEG_INFINITY, f64::max));
+            }
+            if let Some(dark) = &resp.dark {
+                println!("Saved dark rate {:.3} ± {:.3} counts/s (σ {:.3} over {} windows)",
+                         dark.mean, dark.error(), dark.variance.sqrt(), dark.windows);
+            }
+            if let Some(error) = &resp.error {
+                println!("Error: {}", error);
             }
-            let frame_map: HashMap<Frame, f32> =
-                frame_map.iter().map(
-                    |(f, (d, n))| (*f, (*n as f32) / (*d as f32)))
-                .collect();
-            let mut image = Vec::new();
-            image.resize(resolution * resolution, 0.0);
-            for i in 0 .. (image.len() as Frame) {
-                if frame_map.contains_key(&i) {
-                    image[i as usize] = frame_map[&i];
+            true
+        },
+        Receivable::Response(Response::Displays(resp)) => {
+            for connector in &resp.connectors {
+                println!("\x1B[1m{}\x1B[0m ({})", connector.name,
+                         if connector.connected { "connected" } else { "disconnected" });
+                if let Some(edid) = &connector.edid {
+                    println!("  {} {:04x} {:?}", edid.manufacturer, edid.product,
+                             edid.name.as_deref().unwrap_or(""));
+                }
+                for mode in &connector.modes {
+                    println!("  {}", mode);
                 }
             }
-
-            let mut min_value: FloatOrd<f32> = FloatOrd(1000000000.0);
-            let mut max_value: FloatOrd<f32> = FloatOrd(0.0);
-            for value in image.iter() {
-                min_value = std::cmp::min(min_value, FloatOrd(*value as f32));
-                max_value = std::cmp::max(max_value, FloatOrd(*value as f32));
+            println!("Current display: {} on {}", resp.current, resp.current.card);
+            if let Some(error) = &resp.error {
+                println!("Error: {}", error);
             }
-            exr::prelude::write_rgba_file(
-                format!("{}.exr", &filename_prefix),
-                resolution, resolution,
-                |x, y| {
-                    let intensity = image[x + y * resolution];
-                    let adjusted =
-                        (intensity - min_value.0) / (max_value.0 - min_value.0);
-                    (adjusted, adjusted, adjusted, f16::from_f32(1.0))
+            true
+        },
+        Receivable::Response(Response::Calibrate(CalibrateResp::Latency(latency))) => {
+            println!("Latency: {:.0} scanlines ({:.0} µs) over {} steps",
+                     latency.lines, latency.micros, latency.steps);
+            println!("Rise: {:.0} µs, fall: {:.0} µs", latency.rise, latency.fall);
+            println!("Count rates: {:.1} dark, {:.1} bright counts/s",
+                     latency.dark, latency.bright);
+            true
+        },
+        Receivable::Response(Response::Calibrate(CalibrateResp::Aperture(aperture))) => {
+            let (x, y) = aperture.center;
+            println!("Aperture: centre ({:.0}, {:.0}) from the screen centre, radius {:.0}",
+                     x, y, aperture.radius);
+            if let Some((cxx, cyy, cxy)) = aperture.ellipse {
+                println!("Ellipse: ellipse {:.0} {:.0} {:e} {:e} {:e}",
+                         x, y, cxx, cyy, cxy);
+            }
+            true
+        },
+        Receivable::Response(Response::Calibrate(CalibrateResp::Response(curves))) => {
+            for curve in curves {
+                let points: Vec<String> = curve.points.iter()
+                    .map(|(level, transmission)| format!("{}:{:.3}", level, transmission))
+                    .collect();
+                println!("{:?}: gamma {:.2}, {}", curve.channel, curve.gamma, points.join(" "));
+            }
+            true
+        },
+        Receivable::Response(Response::Profile(resp)) => {
+            if let Some(profile) = &resp.profile {
+                println!("{:#?}", profile);
+                let store = crate::profile::Store::new(crate::profile::LAPTOP_PROFILES);
+                if let Err(e) = store.put(profile) {
+                    println!("Failed to save profile locally: {}", e);
                 }
-            ).unwrap();
-            println!("Minimum value: {}", min_value.0);
-            println!("Maximum value: {}", max_value.0);
-            println!("Wrote to {}.exr", filename_prefix);
+            }
+            println!("Stored versions: {:?}", resp.versions);
+            if let Some(error) = &resp.error {
+                println!("Error: {}", error);
+            }
             true
         },
         Receivable::Response(response) => {
@@ -0,0 +1,371 @@
+use crate::lcd::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::edid::Edid;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// How the colour filters of one pixel are laid out on the panel, as seen in
+/// framebuffer coordinates (so a panel mounted sideways has vertical stripes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubpixelLayout {
+    /// Side by side along x, listed left to right.
+    Horizontal([Channel; 3]),
+    /// Stacked along y, listed top to bottom.
+    Vertical([Channel; 3]),
+}
+
+/// Whether a mask drives whole pixels or each subpixel separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    Pixel,
+    /// Triples the resolution across the stripes; the detector is
+    /// monochrome, so all three subpixels are equally useful shutters.
+    Subpixel,
+}
+
+/// Where each byte of the framebuffer sits on the glass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayGeometry {
+    /// Size in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// Channel stored at each byte of a pixel, in memory order.
+    pub bytes: [Channel; 3],
+    pub layout: SubpixelLayout,
+}
+
+impl Default for DisplayGeometry {
+    /// The Pi LCD in its native mode.
+    fn default() -> Self {
+        DisplayGeometry::new(&DisplayConfig::default())
+    }
+}
+
+impl DisplayGeometry {
+    /// The Pi LCD in the mode of `config`: DRM's `Rgb888` stores blue first,
+    /// and the panel has horizontal RGB stripes in its native portrait mode.
+    /// A mode with the sides swapped shows the panel turned a quarter
+    /// clockwise, which stacks the stripes top to bottom.
+    pub fn new(config: &DisplayConfig) -> Self {
+        let (width, height) = (config.size.0 as u32, config.size.1 as u32);
+        let stripes = [Channel::Red, Channel::Green, Channel::Blue];
+        let rotated = (width > height) != (DISPLAY_WIDTH > DISPLAY_HEIGHT);
+        DisplayGeometry {
+            width,
+            height,
+            bytes: [Channel::Blue, Channel::Green, Channel::Red],
+            layout: if rotated {
+                SubpixelLayout::Vertical(stripes)
+            } else {
+                SubpixelLayout::Horizontal(stripes)
+            },
+        }
+    }
+
+    /// Bytes per framebuffer row.
+    pub fn stride(&self) -> u32 {
+        3 * self.width
+    }
+
+    /// Number of independently addressable elements along x and y.
+    pub fn resolution(&self, addressing: Addressing) -> (u32, u32) {
+        match (addressing, self.layout) {
+            (Addressing::Pixel, _) => (self.width, self.height),
+            (Addressing::Subpixel, SubpixelLayout::Horizontal(_)) => {
+                (3 * self.width, self.height)
+            },
+            (Addressing::Subpixel, SubpixelLayout::Vertical(_)) => {
+                (self.width, 3 * self.height)
+            },
+        }
+    }
+
+    /// Offset of the center of the subpixel stored at `byte` from the upper
+    /// left corner of its pixel, in pixels.
+    pub fn subpixel_center(&self, byte: usize) -> (f64, f64) {
+        let channel = self.bytes[byte];
+        let position = |order: &[Channel; 3]| {
+            let index = order.iter().position(|c| *c == channel).unwrap();
+            (index as f64 + 0.5) / 3.0
+        };
+        match &self.layout {
+            SubpixelLayout::Horizontal(order) => (position(order), 0.5),
+            SubpixelLayout::Vertical(order) => (0.5, position(order)),
+        }
+    }
+
+    /// Framebuffer offset of `byte` of pixel `(x, y)`.
+    pub fn offset(&self, x: u32, y: u32, byte: usize) -> usize {
+        (y * self.stride() + 3 * x) as usize + byte
+    }
+}
+
+/// A display mode offered by a connector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeInfo {
+    pub name: String,
+    /// Width and height in pixels.
+    pub size: (u16, u16),
+    /// Refresh rate in Hz.
+    pub refresh: u32,
+    pub preferred: bool,
+}
+
+impl std::fmt::Display for ModeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}@{}", self.size.0, self.size.1, self.refresh)?;
+        if self.preferred {
+            write!(f, " (preferred)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A connector of the DRM card and the modes it offers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectorInfo {
+    /// Interface and index, e.g. `HDMIA-1`.
+    pub name: String,
+    pub connected: bool,
+    pub modes: Vec<ModeInfo>,
+    /// What the attached panel reports, if anything.
+    pub edid: Option<Edid>,
+}
+
+/// Which connector and mode to drive the modulator with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayConfig {
+    /// DRM device node.
+    pub card: String,
+    /// Connector name; the first connected one offering the mode if `None`.
+    pub connector: Option<String>,
+    /// Mode width and height in pixels, as the panel reports them, so
+    /// portrait panels are taller than they are wide.
+    pub size: (u16, u16),
+    /// Refresh rate in Hz; the fastest available if `None`.
+    pub refresh: Option<u32>,
+    /// Refuse panels with no EDID or one that isn't the modulator's.
+    pub check_edid: bool,
+}
+
+impl Default for DisplayConfig {
+    /// The modulator panel in its native portrait mode.
+    fn default() -> Self {
+        DisplayConfig {
+            card: String::from("/dev/dri/card0"),
+            connector: None,
+            size: (DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16),
+            refresh: None,
+            check_edid: true,
+        }
+    }
+}
+
+impl std::fmt::Display for DisplayConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(connector) = &self.connector {
+            write!(f, "{}:", connector)?;
+        }
+        write!(f, "{}x{}", self.size.0, self.size.1)?;
+        if let Some(refresh) = self.refresh {
+            write!(f, "@{}", refresh)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for DisplayConfig {
+    type Err = DisplayError;
+
+    /// Parses `[CARD:][CONNECTOR:]WIDTHxHEIGHT[@HZ]`, where the card is a
+    /// device node such as `/dev/dri/card1`; the default card if omitted.
+    fn from_str(string: &str) -> Result<Self, DisplayError> {
+        let invalid = || DisplayError::Invalid(string.to_string());
+        let (card, rest) = match string.split_once(':') {
+            Some((card, rest)) if card.starts_with('/') => (Some(card.to_string()), rest),
+            _ => (None, string),
+        };
+        let (connector, rest) = match rest.split_once(':') {
+            Some((connector, rest)) => (Some(connector.to_string()), rest),
+            None => (None, rest),
+        };
+        let (size, refresh) = match rest.split_once('@') {
+            Some((size, refresh)) => {
+                (size, Some(refresh.parse().map_err(|_| invalid())?))
+            },
+            None => (rest, None),
+        };
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let defaults = DisplayConfig::default();
+        Ok(DisplayConfig {
+            card: card.unwrap_or(defaults.card),
+            connector,
+            size: (width.parse().map_err(|_| invalid())?,
+                   height.parse().map_err(|_| invalid())?),
+            refresh,
+            ..defaults
+        })
+    }
+}
+
+impl DisplayConfig {
+    /// Indices of the connector and mode to use among `connectors`.
+    pub fn choose(&self, connectors: &[ConnectorInfo])
+                  -> Result<(usize, usize), DisplayError>
+    {
+        let names = || connectors.iter().map(|c| c.name.clone()).collect();
+        if let Some(name) = &self.connector {
+            let connector = connectors.iter().find(|c| &c.name == name)
+                .ok_or_else(|| DisplayError::NoConnector(name.clone(), names()))?;
+            if !connector.connected {
+                return Err(DisplayError::Disconnected(name.clone()));
+            }
+        }
+        let mut offered = Vec::new();
+        for (i, connector) in connectors.iter().enumerate() {
+            let wanted = self.connector.as_ref().map_or(true, |name| &connector.name == name);
+            if !wanted || !connector.connected {
+                continue;
+            }
+            let best = connector.modes.iter().enumerate()
+                .filter(|(_, mode)| mode.size == self.size)
+                .filter(|(_, mode)| self.refresh.map_or(true, |hz| mode.refresh == hz))
+                .max_by_key(|(_, mode)| (mode.refresh, mode.preferred));
+            if let Some((j, _)) = best {
+                return Ok((i, j));
+            }
+            offered.extend(connector.modes.iter()
+                           .map(|mode| format!("{} {}", connector.name, mode)));
+        }
+        if offered.is_empty() && connectors.iter().all(|c| !c.connected) {
+            return Err(DisplayError::NoConnector(String::from("connected"), names()));
+        }
+        Err(DisplayError::NoMode(self.clone(), offered))
+    }
+}
+
+/// Why a display couldn't be chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisplayError {
+    /// The requested connector, and the names of those that exist.
+    NoConnector(String, Vec<String>),
+    Disconnected(String),
+    /// No connected connector offers the configured mode; lists those
+    /// that are offered.
+    NoMode(DisplayConfig, Vec<String>),
+    /// The connector has no usable crtc.
+    NoCrtc(String),
+    /// A display specification that didn't parse.
+    Invalid(String),
+}
+
+impl std::fmt::Display for DisplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisplayError::NoConnector(name, names) => {
+                write!(f, "No {} connector; the card has {:?}", name, names)
+            },
+            DisplayError::Disconnected(name) => {
+                write!(f, "Connector {} has nothing attached", name)
+            },
+            DisplayError::NoMode(config, offered) => {
+                write!(f, "No connector offers {}; available modes: {}",
+                       config, offered.join(", "))
+            },
+            DisplayError::NoCrtc(name) => {
+                write!(f, "No crtc can drive connector {}", name)
+            },
+            DisplayError::Invalid(string) => {
+                write!(f, "Expected [CARD:][CONNECTOR:]WIDTHxHEIGHT[@HZ], got {:?}", string)
+            },
+        }
+    }
+}
+
+impl std::error::Error for DisplayError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subpixel_addressing_triples_resolution_across_stripes() {
+        let geometry = DisplayGeometry::default();
+        assert_eq!(geometry.resolution(Addressing::Pixel), (1440, 2560));
+        assert_eq!(geometry.resolution(Addressing::Subpixel), (4320, 2560));
+        let sideways = DisplayGeometry {
+            layout: SubpixelLayout::Vertical([Channel::Red, Channel::Green, Channel::Blue]),
+            ..geometry
+        };
+        assert_eq!(sideways.resolution(Addressing::Subpixel), (1440, 7680));
+        let landscape = DisplayGeometry::new(&"2560x1440".parse().unwrap());
+        assert_eq!(landscape, DisplayGeometry { width: 2560, height: 1440, ..sideways });
+    }
+
+    #[test]
+    fn rgb888_stores_rightmost_subpixel_first() {
+        let geometry = DisplayGeometry::default();
+        let x = |byte| geometry.subpixel_center(byte).0;
+        assert!(x(2) < x(1) && x(1) < x(0));
+        assert_eq!(geometry.offset(1, 1, 2), 4320 + 5);
+    }
+
+    fn hdmi(connected: bool, modes: &[((u16, u16), u32, bool)]) -> ConnectorInfo {
+        ConnectorInfo {
+            name: String::from("HDMIA-1"),
+            connected,
+            modes: modes.iter().map(|&(size, refresh, preferred)| ModeInfo {
+                name: format!("{}x{}", size.0, size.1),
+                size,
+                refresh,
+                preferred,
+            }).collect(),
+            edid: None,
+        }
+    }
+
+    #[test]
+    fn parses_display_specifications() {
+        let config: DisplayConfig = "HDMIA-1:2560x1440@60".parse().unwrap();
+        assert_eq!(config.connector.as_deref(), Some("HDMIA-1"));
+        assert_eq!((config.size, config.refresh), ((2560, 1440), Some(60)));
+        assert_eq!(config.to_string(), "HDMIA-1:2560x1440@60");
+        let config: DisplayConfig = "1440x2560".parse().unwrap();
+        assert_eq!(config, DisplayConfig::default());
+        let config: DisplayConfig = "/dev/dri/card1:DSI-1:800x480".parse().unwrap();
+        assert_eq!((config.card.as_str(), config.connector.as_deref()),
+                   ("/dev/dri/card1", Some("DSI-1")));
+        let config: DisplayConfig = "/dev/dri/card1:800x480".parse().unwrap();
+        assert_eq!((config.card.as_str(), config.connector), ("/dev/dri/card1", None));
+        assert!("1440-2560".parse::<DisplayConfig>().is_err());
+    }
+
+    #[test]
+    fn chooses_fastest_matching_mode_in_either_orientation() {
+        let connectors = [
+            hdmi(false, &[((1440, 2560), 60, true)]),
+            ConnectorInfo {
+                name: String::from("HDMIA-2"),
+                ..hdmi(true, &[((1920, 1080), 60, true),
+                               ((2560, 1440), 50, false),
+                               ((2560, 1440), 60, false)])
+            },
+        ];
+        let landscape: DisplayConfig = "2560x1440".parse().unwrap();
+        assert_eq!(landscape.choose(&connectors), Ok((1, 2)));
+        let portrait = DisplayConfig::default();
+        assert!(matches!(portrait.choose(&connectors),
+                         Err(DisplayError::NoMode(_, offered)) if offered.len() == 3));
+        let named: DisplayConfig = "HDMIA-1:1440x2560".parse().unwrap();
+        assert_eq!(named.choose(&connectors),
+                   Err(DisplayError::Disconnected(String::from("HDMIA-1"))));
+        let missing: DisplayConfig = "DSI-1:1440x2560".parse().unwrap();
+        assert!(matches!(missing.choose(&connectors),
+                         Err(DisplayError::NoConnector(..))));
+    }
+}
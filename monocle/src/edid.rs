@@ -0,0 +1,283 @@
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::scanline::ScanLine;
+
+const BLOCK: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// EDID shipped to the Pi firmware for the modulator panel.
+pub const MODULATOR: &[u8] = include_bytes!("../res/modulator.edid");
+
+/// A detailed timing descriptor: one video mode, blanking included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DetailedTiming {
+    /// Pixel clock in kHz.
+    pub pixel_clock: u32,
+    pub h_active: u16,
+    pub h_blank: u16,
+    pub v_active: u16,
+    pub v_blank: u16,
+    pub h_sync_offset: u16,
+    pub h_sync_width: u16,
+    pub v_sync_offset: u16,
+    pub v_sync_width: u16,
+    /// Image size in millimetres.
+    pub size_mm: (u16, u16),
+    pub interlaced: bool,
+}
+
+impl DetailedTiming {
+    fn parse(bytes: &[u8]) -> DetailedTiming {
+        let low = |i: usize| bytes[i] as u16;
+        DetailedTiming {
+            pixel_clock: 10 * u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            h_active: low(2) | (low(4) >> 4) << 8,
+            h_blank: low(3) | (low(4) & 0xf) << 8,
+            v_active: low(5) | (low(7) >> 4) << 8,
+            v_blank: low(6) | (low(7) & 0xf) << 8,
+            h_sync_offset: low(8) | (low(11) >> 6 & 0x3) << 8,
+            h_sync_width: low(9) | (low(11) >> 4 & 0x3) << 8,
+            v_sync_offset: low(10) >> 4 | (low(11) >> 2 & 0x3) << 4,
+            v_sync_width: low(10) & 0xf | (low(11) & 0x3) << 4,
+            size_mm: (low(12) | (low(14) >> 4) << 8, low(13) | (low(14) & 0xf) << 8),
+            interlaced: bytes[17] & 0x80 != 0,
+        }
+    }
+
+    /// Active size in pixels.
+    pub fn size(&self) -> (u16, u16) {
+        (self.h_active, self.v_active)
+    }
+
+    pub fn h_total(&self) -> u32 {
+        (self.h_active + self.h_blank) as u32
+    }
+
+    pub fn v_total(&self) -> u32 {
+        (self.v_active + self.v_blank) as u32
+    }
+
+    /// Refresh rate in Hz.
+    pub fn refresh_rate(&self) -> f64 {
+        1000.0 * self.pixel_clock as f64 / (self.h_total() * self.v_total()) as f64
+    }
+
+    /// Time to scan out one line, blanking included.
+    pub fn line_duration(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.h_total() as f64 / (1000.0 * self.pixel_clock as f64))
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        self.line_duration() * self.v_total()
+    }
+
+    /// Time from the start of the first active line to the start of `line`.
+    pub fn scanline_time(&self, line: ScanLine) -> Duration {
+        self.line_duration() * line
+    }
+}
+
+/// The parts of an EDID that identify a panel and its modes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edid {
+    /// Three letter PNP ID.
+    pub manufacturer: String,
+    pub product: u16,
+    pub serial: u32,
+    /// Week and year of manufacture.
+    pub manufactured: (u8, u16),
+    pub version: (u8, u8),
+    pub name: Option<String>,
+    pub serial_text: Option<String>,
+    /// Detailed timings of the base block and then of CEA extensions; the
+    /// first is the preferred mode.
+    pub timings: Vec<DetailedTiming>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdidError {
+    /// Not a whole number of blocks, or fewer than the base block says.
+    Length(usize),
+    Header,
+    /// Index of the block whose bytes don't sum to zero.
+    Checksum(usize),
+    /// Index of the extension block whose descriptors start past its end.
+    Extension(usize),
+    /// The connector exposes no EDID.
+    Missing,
+    /// What differs from the expected panel.
+    Mismatch(Vec<String>),
+}
+
+impl std::fmt::Display for EdidError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EdidError::Length(length) => write!(f, "EDID of {} bytes is truncated", length),
+            EdidError::Header => write!(f, "EDID header is missing"),
+            EdidError::Checksum(block) => write!(f, "EDID block {} has a bad checksum", block),
+            EdidError::Extension(block) => {
+                write!(f, "EDID block {} has an out of range descriptor offset", block)
+            },
+            EdidError::Missing => write!(f, "Connector has no EDID"),
+            EdidError::Mismatch(differences) => {
+                write!(f, "Panel isn't the modulator: {}", differences.join("; "))
+            },
+        }
+    }
+}
+
+impl std::error::Error for EdidError {}
+
+// Text of a display descriptor, which is padded with a newline and spaces.
+fn descriptor_text(bytes: &[u8]) -> String {
+    let text: String = bytes[5 .. 18].iter()
+        .take_while(|b| **b != b'\n')
+        .map(|b| *b as char)
+        .collect();
+    text.trim_end().to_string()
+}
+
+impl Edid {
+    pub fn parse(bytes: &[u8]) -> Result<Edid, EdidError> {
+        if bytes.len() < BLOCK || bytes.len() % BLOCK != 0 {
+            return Err(EdidError::Length(bytes.len()));
+        }
+        if bytes[.. 8] != HEADER {
+            return Err(EdidError::Header);
+        }
+        let blocks = 1 + bytes[126] as usize;
+        if bytes.len() < blocks * BLOCK {
+            return Err(EdidError::Length(bytes.len()));
+        }
+        for (i, block) in bytes.chunks(BLOCK).take(blocks).enumerate() {
+            if block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(EdidError::Checksum(i));
+            }
+        }
+
+        let id = u16::from_be_bytes([bytes[8], bytes[9]]);
+        let letter = |shift: u16| (b'A' - 1 + (id >> shift & 0x1f) as u8) as char;
+        let mut edid = Edid {
+            manufacturer: [letter(10), letter(5), letter(0)].iter().collect(),
+            product: u16::from_le_bytes([bytes[10], bytes[11]]),
+            serial: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            manufactured: (bytes[16], 1990 + bytes[17] as u16),
+            version: (bytes[18], bytes[19]),
+            name: None,
+            serial_text: None,
+            timings: Vec::new(),
+        };
+
+        for descriptor in bytes[54 .. 126].chunks(18) {
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                edid.timings.push(DetailedTiming::parse(descriptor));
+            } else if descriptor[3] == 0xfc {
+                edid.name = Some(descriptor_text(descriptor));
+            } else if descriptor[3] == 0xff {
+                edid.serial_text = Some(descriptor_text(descriptor));
+            }
+        }
+
+        // CEA-861 extensions list further timings from the offset in byte 2
+        // up to the checksum.
+        for (i, block) in bytes.chunks(BLOCK).take(blocks).enumerate().skip(1) {
+            if block[0] != 0x02 || block[2] < 4 {
+                continue;
+            }
+            if block[2] as usize > BLOCK - 1 {
+                return Err(EdidError::Extension(i));
+            }
+            for descriptor in block[block[2] as usize .. BLOCK - 1].chunks_exact(18) {
+                if descriptor[0] == 0 && descriptor[1] == 0 {
+                    break;
+                }
+                edid.timings.push(DetailedTiming::parse(descriptor));
+            }
+        }
+
+        Ok(edid)
+    }
+
+    /// The EDID the modulator panel is driven with.
+    pub fn modulator() -> Edid {
+        Edid::parse(MODULATOR).unwrap()
+    }
+
+    pub fn preferred(&self) -> Option<&DetailedTiming> {
+        self.timings.first()
+    }
+
+    /// The timing with the given active size, preferring earlier ones.
+    pub fn timing(&self, size: (u16, u16)) -> Option<&DetailedTiming> {
+        self.timings.iter().find(|timing| timing.size() == size)
+    }
+
+    /// Checks that this describes the same panel as `expected`, down to its
+    /// preferred timing.
+    pub fn check(&self, expected: &Edid) -> Result<(), EdidError> {
+        let mut differences = Vec::new();
+        if (&self.manufacturer, self.product) != (&expected.manufacturer, expected.product) {
+            differences.push(format!(
+                "product {}:{:04x} instead of {}:{:04x}",
+                self.manufacturer, self.product, expected.manufacturer, expected.product));
+        }
+        if self.name != expected.name {
+            differences.push(format!("name {:?} instead of {:?}", self.name, expected.name));
+        }
+        if self.preferred() != expected.preferred() {
+            differences.push(format!("preferred timing {:?} instead of {:?}",
+                                     self.preferred(), expected.preferred()));
+        }
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(EdidError::Mismatch(differences))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_modulator_edid() {
+        let edid = Edid::modulator();
+        assert_eq!(edid.manufacturer, "LZT");
+        assert_eq!(edid.name.as_deref(), Some("YX"));
+        // One timing in the base block and five in the CEA extension.
+        assert_eq!(edid.timings.len(), 6);
+        let timing = edid.preferred().unwrap();
+        assert_eq!(timing.size(), (1440, 2560));
+        assert_eq!((timing.h_total(), timing.v_total()), (1588, 2594));
+        assert_eq!(timing.size_mm, (68, 121));
+        assert!((timing.refresh_rate() - 40.0).abs() < 0.01);
+        assert_eq!(edid.check(&Edid::modulator()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_corrupt_and_foreign_edids() {
+        let mut bytes = MODULATOR.to_vec();
+        bytes[20] ^= 1;
+        assert_eq!(Edid::parse(&bytes), Err(EdidError::Checksum(0)));
+        assert_eq!(Edid::parse(&bytes[.. 100]), Err(EdidError::Length(100)));
+        let mut bytes = MODULATOR.to_vec();
+        bytes[BLOCK + 2] = 200;
+        let sum = bytes[BLOCK .. 2 * BLOCK - 1].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[2 * BLOCK - 1] = sum.wrapping_neg();
+        assert_eq!(Edid::parse(&bytes), Err(EdidError::Extension(1)));
+        let differences = |other: Edid| match other.check(&Edid::modulator()) {
+            Err(EdidError::Mismatch(differences)) => differences,
+            result => panic!("{:?}", result),
+        };
+        let mut other = Edid::modulator();
+        other.product += 1;
+        let found = differences(other);
+        assert!(found.len() == 1 && found[0].starts_with("product"), "{:?}", found);
+        let mut other = Edid::modulator();
+        other.timings.clear();
+        let found = differences(other);
+        assert!(found.len() == 1 && found[0].starts_with("preferred timing"), "{:?}", found);
+    }
+}
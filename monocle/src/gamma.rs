@@ -0,0 +1,108 @@
+use serde_derive::{Deserialize, Serialize};
+use crate::display::Channel;
+use crate::mask::Lut;
+
+/// Byte levels each channel is measured at during a response sweep.
+pub fn sweep_levels() -> Vec<u8> {
+    (16 ..= 240).step_by(16).map(|level| level as u8).chain([255]).collect()
+}
+
+/// Transmission of one channel of the panel as a function of the byte
+/// written, relative to 255 and above the black level.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponseCurve {
+    pub channel: Channel,
+    /// Increasing byte levels and their transmissions, from `(0, 0.0)` to
+    /// `(255, 1.0)` and never decreasing.
+    pub points: Vec<(u8, f64)>,
+    /// Exponent of the power law closest to the points, for reference.
+    pub gamma: f64,
+}
+
+impl ResponseCurve {
+    /// Fits a curve to the mean count measured at each level, given the
+    /// count with the panel black. Noise that would make the curve decrease
+    /// is flattened out.
+    pub fn fit(channel: Channel, dark: f64, counts: &[(u8, f64)]) -> Option<ResponseCurve> {
+        let full = counts.iter().find(|(level, _)| *level == 255)?.1 - dark;
+        if full <= 0.0 {
+            return None;
+        }
+        let mut points = vec![(0, 0.0)];
+        let mut counts = counts.to_vec();
+        counts.sort_by_key(|(level, _)| *level);
+        for (level, count) in counts {
+            if level == 0 {
+                continue;
+            }
+            let previous = points.last().unwrap().1;
+            let transmission = ((count - dark) / full).clamp(previous, 1.0);
+            points.push((level, if level == 255 { 1.0 } else { transmission }));
+        }
+
+        // Least squares in log space, over the points strictly inside.
+        let (mut xy, mut xx) = (0.0, 0.0);
+        for (level, transmission) in &points {
+            if *level == 0 || *level == 255 || *transmission <= 0.0 {
+                continue;
+            }
+            let x = (*level as f64 / 255.0).ln();
+            xy += x * transmission.ln();
+            xx += x * x;
+        }
+        let gamma = if xx > 0.0 { xy / xx } else { 1.0 };
+        Some(ResponseCurve { channel, points, gamma })
+    }
+
+    /// Transmission at `level`, interpolating linearly between points.
+    pub fn transmission(&self, level: u8) -> f64 {
+        let after = self.points.iter()
+            .position(|(l, _)| *l >= level)
+            .unwrap_or(self.points.len() - 1);
+        let (l1, t1) = self.points[after];
+        if after == 0 || l1 == level {
+            return t1;
+        }
+        let (l0, t0) = self.points[after - 1];
+        t0 + (t1 - t0) * (level - l0) as f64 / (l1 - l0) as f64
+    }
+
+    /// The byte to write for each transmission level, so that masks drawn
+    /// through it transmit in proportion to their values.
+    pub fn inverse(&self) -> Lut {
+        let transmissions: Vec<f64> = (0 ..= 255).map(|l| self.transmission(l)).collect();
+        Lut((0 ..= 255).map(|target| {
+            let target = target as f64 / 255.0;
+            (0 ..= 255u8)
+                .min_by(|a, b| {
+                    let error = |l: &u8| (transmissions[*l as usize] - target).abs();
+                    error(a).partial_cmp(&error(b)).unwrap()
+                })
+                .unwrap()
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_linearises_simulated_panel() {
+        let (dark, bright, gamma) = (40.0, 2040.0, 2.2);
+        let counts: Vec<(u8, f64)> = sweep_levels().into_iter()
+            .map(|level| (level, dark + bright * (level as f64 / 255.0).powf(gamma)))
+            .collect();
+        let curve = ResponseCurve::fit(Channel::Green, dark, &counts).unwrap();
+        assert!((curve.gamma - gamma).abs() < 0.05, "{:?}", curve);
+
+        let lut = curve.inverse();
+        assert_eq!((lut.apply(0), lut.apply(255)), (0, 255));
+        for level in 0 ..= 255u8 {
+            let byte = lut.apply(level) as f64 / 255.0;
+            // Within the interpolation error of the sweep's spacing.
+            assert!((byte.powf(gamma) - level as f64 / 255.0).abs() < 0.02,
+                    "{} maps to {}", level, lut.apply(level));
+        }
+    }
+}
@@ -1,21 +1,145 @@
 use std::fs::OpenOptions;
 use std::error::Error;
 use std::collections::BTreeSet;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 use memmap::MmapOptions;
 use serde_derive::{Deserialize, Serialize};
 
+/// Window length of captures from before readings carried their duration,
+/// whose counts were rescaled to it.
+pub const LEGACY_WINDOW: Duration = Duration::from_micros(100);
+
+/// The counter is 12 bits wide, so a window must see fewer pulses than this.
+pub const COUNTER_WRAP: u32 = 4096;
+
+/// How `record_window` waits out a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WindowTiming {
+    /// Spin on the monotonic clock: precise, but keeps a core busy.
+    BusyWait,
+    /// Block on a timerfd, which frees the core but wakes up late by a few
+    /// tens of microseconds.
+    TimerFd,
+    /// Sample the counter back to back and close a window at the first
+    /// sample after its length, so that windows abut and no pulse goes
+    /// uncounted between them. A length of zero keeps every sample.
+    Continuous,
+}
+
+/// Length and timing of counting windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowConfig {
+    pub micros: u32,
+    pub timing: WindowTiming,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig { micros: 100, timing: WindowTiming::BusyWait }
+    }
+}
+
+impl WindowConfig {
+    pub fn length(&self) -> Duration {
+        Duration::from_micros(self.micros as u64)
+    }
+
+    /// Only continuous windows can be zero long: a timerfd set to zero
+    /// never fires.
+    pub fn is_valid(&self) -> bool {
+        self.micros > 0 || self.timing == WindowTiming::Continuous
+    }
+}
+
+// A one-shot timerfd on the monotonic clock.
+struct Timer(RawFd);
+
+impl Timer {
+    fn new() -> std::io::Result<Timer> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Timer(fd))
+    }
+
+    fn sleep(&self, duration: Duration) -> std::io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_value: libc::timespec {
+                tv_sec: duration.as_secs() as libc::time_t,
+                tv_nsec: duration.subsec_nanos() as libc::c_long,
+            },
+        };
+        if unsafe { libc::timerfd_settime(self.0, 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut expirations = 0u64;
+        let read = unsafe {
+            libc::read(self.0, &mut expirations as *mut u64 as *mut libc::c_void, 8)
+        };
+        if read != 8 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
 pub struct GPIO {
     file: std::fs::File,
     mmap: memmap::Mmap,
+    pins: PinMap,
+    window: WindowConfig,
+    timer: Option<Timer>,
+    /// Reading start times are counted from here.
+    epoch: Instant,
+    /// Last raw counter value and the unwrapped total, for `sample`.
+    total: Option<(u32, u64)>,
 }
 
 impl GPIO {
+    /// Opens the GPIO registers, reading the counter through the pins of the
+    /// current calibration profile.
     pub fn new() -> Result<Self, Box<dyn Error>> {
+        GPIO::with_pins(crate::profile::current().pins())
+    }
+
+    pub fn with_pins(pins: PinMap) -> Result<Self, Box<dyn Error>> {
+        pins.check()?;
         let gpio = OpenOptions::new().read(true).open("/dev/gpiomem")?;
         let gpio_mmap = unsafe {
             MmapOptions::new().offset(0).len(1024).map(&gpio)?
         };
-        Ok(GPIO { file: gpio, mmap: gpio_mmap })
+        Ok(GPIO {
+            file: gpio,
+            mmap: gpio_mmap,
+            pins,
+            window: WindowConfig::default(),
+            timer: None,
+            epoch: Instant::now(),
+            total: None,
+        })
+    }
+
+    /// Switches to windows of `window`, creating a timerfd if it asks for one.
+    pub fn set_window(&mut self, window: WindowConfig) -> std::io::Result<()> {
+        if !window.is_valid() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, "Counting windows can't be zero long"));
+        }
+        self.timer = match window.timing {
+            WindowTiming::BusyWait | WindowTiming::Continuous => None,
+            WindowTiming::TimerFd => Some(Timer::new()?),
+        };
+        self.window = window;
+        Ok(())
     }
 
     pub fn read_gpio(&self) -> u32 {
@@ -23,54 +147,226 @@ impl GPIO {
         unsafe { transmuted.offset(13).read_volatile() }
     }
 
-    // Records the number of pulses in a 100 microsecond time window
-    pub fn record_window(&self) -> Option<Reading> {
-        let before_time = std::time::Instant::now();
-        let before_state = self.read_gpio();
-        std::thread::sleep(std::time::Duration::from_micros(100));
-        let after_time = std::time::Instant::now();
-        let after_state = self.read_gpio();
-        let duration = after_time.duration_since(before_time);
-        let before = compute_counter(before_state);
-        let after = compute_counter(after_state);
-        let difference = if after.counter < before.counter {
-            before.counter + (4096 - after.counter)
-        } else {
-            after.counter - before.counter
-        };
-        let ratio = duration.as_micros() as f64 / 100.0;
-        if ratio > 2.5 {
-            return None;
+    /// Counts pulses over one window, returning the raw count and how long
+    /// the window really lasted between the two reads of the counter.
+    pub fn record_window(&self) -> Reading {
+        let length = self.window.length();
+        let start = Instant::now();
+        let before = self.pins.decode(self.read_gpio());
+        let slept = self.timer.as_ref().map(|timer| timer.sleep(length).is_ok());
+        if slept != Some(true) {
+            while start.elapsed() < length {
+                std::hint::spin_loop();
+            }
         }
-        Some(Reading {
+        let after = self.pins.decode(self.read_gpio());
+        let end = Instant::now();
+        Reading {
             overlight: before.overlight || after.overlight,
-            counter: (difference as f64 * ratio) as u32,
-        })
+            counter: unwrap(before.counter, after.counter) as u64,
+            start: start.duration_since(self.epoch).as_nanos() as u64,
+            duration: end.duration_since(start).as_nanos() as u64,
+        }
+    }
+
+    /// Reads the counter once, unwrapping it into the number of pulses
+    /// since the first sample. Samples must be taken often enough that the
+    /// counter never wraps twice between them.
+    pub fn sample(&mut self) -> Sample {
+        let state = self.pins.decode(self.read_gpio());
+        let time = self.epoch.elapsed().as_nanos() as u64;
+        let count = match self.total {
+            None => 0,
+            Some((last, total)) => total + unwrap(last, state.counter) as u64,
+        };
+        self.total = Some((state.counter, count));
+        Sample { time, count, overlight: state.overlight }
+    }
+}
+
+/// Pulses between two raw counter values, assuming it wrapped at most once.
+pub fn unwrap(before: u32, after: u32) -> u32 {
+    (after + COUNTER_WRAP - before) % COUNTER_WRAP
+}
+
+/// The counter at one instant of a continuous run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sample {
+    /// Nanoseconds since the counter was opened.
+    pub time: u64,
+    /// Pulses since the first sample.
+    pub count: u64,
+    pub overlight: bool,
+}
+
+impl Sample {
+    /// The window from this sample to a later one.
+    pub fn until(&self, later: &Sample) -> Reading {
+        Reading {
+            overlight: self.overlight || later.overlight,
+            counter: later.count - self.count,
+            start: self.time,
+            duration: later.time - self.time,
+        }
+    }
+}
+
+/// Merges consecutive windows into windows at least `length` long, as if
+/// they had been counted over those. Windows are only merged if they abut
+/// and `joinable` holds for their tags; each merged window keeps the tag of
+/// its first.
+pub fn rebin<T: Clone>(
+    readings: &[(T, Reading)],
+    length: Duration,
+    joinable: impl Fn(&T, &T) -> bool,
+) -> Vec<(T, Reading)> {
+    let length = length.as_nanos() as u64;
+    let mut merged: Vec<(T, Reading)> = Vec::new();
+    let mut open = false;
+    for (tag, reading) in readings {
+        if let Some((first, bin)) = merged.last_mut() {
+            let end = bin.start + bin.duration;
+            if open && end == reading.start && joinable(first, tag) {
+                bin.overlight |= reading.overlight;
+                bin.counter += reading.counter;
+                bin.duration += reading.duration;
+                open = bin.duration < length;
+                continue;
+            }
+        }
+        merged.push((tag.clone(), reading.clone()));
+        open = reading.duration < length;
     }
+    merged
 }
 
+/// Pulses counted over one window.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct Reading {
+    pub overlight: bool,
+    /// Raw count, except in legacy captures; see `duration`.
+    pub counter: u64,
+    /// Start of the window in nanoseconds since the counter was opened.
+    #[serde(default)]
+    pub start: u64,
+    /// Length of the window in nanoseconds. Zero in captures from before
+    /// this was recorded, whose counts were rescaled to [`LEGACY_WINDOW`].
+    #[serde(default)]
+    pub duration: u64,
+}
+
+impl Reading {
+    pub fn seconds(&self) -> f64 {
+        if self.duration == 0 {
+            LEGACY_WINDOW.as_secs_f64()
+        } else {
+            self.duration as f64 * 1e-9
+        }
+    }
+
+    /// Counts per second.
+    pub fn rate(&self) -> f64 {
+        self.counter as f64 / self.seconds()
+    }
+}
+
+/// Outputs of the counter at one instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterState {
     pub overlight: bool,
     pub counter: u32,
 }
 
-pub fn compute_counter(gpio: u32) -> Reading {
-    let overlight: bool = ((gpio >> 2) & 1) == 1;
-    let q1: u32 = (gpio >> 17) & 1;
-    let q2: u32 = (gpio >> 5) & 1;
-    let q3: u32 = (gpio >> 6) & 1;
-    let q4: u32 = (gpio >> 13) & 1;
-    let q5: u32 = (gpio >> 26) & 1;
-    let q6: u32 = (gpio >> 12) & 1;
-    let q7: u32 = (gpio >> 19) & 1;
-    let q8: u32 = (gpio >> 22) & 1;
-    let q9: u32 = (gpio >> 18) & 1;
-    let q10: u32 = (gpio >> 23) & 1;
-    let q11: u32 = (gpio >> 24) & 1;
-    let q12: u32 = (gpio >> 16) & 1;
-    let counter = q1 + (q2 << 1) + (q3 << 2) + (q4 << 3) + (q5 << 4)
-        + (q6 << 5) + (q7 << 6) + (q8 << 7) + (q9 << 8) + (q10 << 9)
-        + (q11 << 10) + (q12 << 11);
-    Reading { overlight, counter }
+/// GPIO lines wired to the pulse counter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinMap {
+    pub overlight: u8,
+    /// Outputs Q1 to Q12, least significant first.
+    pub counter: [u8; 12],
+}
+
+impl Default for PinMap {
+    fn default() -> Self {
+        PinMap {
+            overlight: 2,
+            counter: [17, 5, 6, 13, 26, 12, 19, 22, 18, 23, 24, 16],
+        }
+    }
+}
+
+impl PinMap {
+    /// GPIO lines in the bank the counter is read from.
+    pub const LINES: u8 = 28;
+
+    /// Fails if a pin isn't in the bank, which [`decode`](Self::decode)
+    /// couldn't shift by.
+    pub fn check(&self) -> Result<(), String> {
+        let mut pins = std::iter::once(&self.overlight).chain(&self.counter);
+        match pins.find(|pin| **pin >= PinMap::LINES) {
+            Some(pin) => Err(format!("GPIO {} isn't below {}", pin, PinMap::LINES)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn decode(&self, gpio: u32) -> CounterState {
+        let overlight: bool = ((gpio >> self.overlight) & 1) == 1;
+        let counter = self.counter.iter().enumerate()
+            .map(|(bit, pin)| ((gpio >> pin) & 1) << bit)
+            .sum();
+        CounterState { overlight, counter }
+    }
+}
+
+pub fn compute_counter(gpio: u32) -> CounterState {
+    PinMap::default().decode(gpio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_counter_bits_from_their_pins() {
+        let pins = PinMap::default();
+        assert_eq!(pins.decode(1 << 17 | 1 << 16), CounterState { overlight: false, counter: 2049 });
+        assert_eq!(pins.decode(1 << 2 | 1 << 5), CounterState { overlight: true, counter: 2 });
+    }
+
+    #[test]
+    fn rebins_unwrapped_samples_without_losing_pulses() {
+        // A 12-bit counter read every 700 ns, wrapping several times.
+        let (mut last, mut total) = (4000, 0u64);
+        let mut samples = vec![Sample { time: 0, count: 0, overlight: false }];
+        for i in 1 ..= 100u64 {
+            let raw = (4000 + i * i * 13) as u32 % COUNTER_WRAP;
+            assert!(i * 26 < COUNTER_WRAP as u64);
+            total += unwrap(last, raw) as u64;
+            last = raw;
+            samples.push(Sample { time: i * 700, count: total, overlight: false });
+        }
+        assert_eq!(total, 100 * 100 * 13);
+
+        let windows: Vec<((), Reading)> = samples.windows(2)
+            .map(|pair| ((), pair[0].until(&pair[1])))
+            .collect();
+        let bins = rebin(&windows, Duration::from_micros(10), |_, _| true);
+        assert_eq!(bins.len(), 7);
+        assert!(bins[.. 6].iter().all(|(_, bin)| bin.duration == 10_500));
+        assert_eq!(bins.iter().map(|(_, bin)| bin.counter).sum::<u64>(), total);
+        assert_eq!(bins[6].1.start + bins[6].1.duration, 70_000);
+
+        // Bins can outlast the 4.3 s that fit in 32 bits of nanoseconds.
+        let seconds = |s: u64| Sample { time: s * 1_000_000_000, count: s, overlight: false };
+        let windows = [((), seconds(0).until(&seconds(3))), ((), seconds(3).until(&seconds(6)))];
+        let bins = rebin(&windows, Duration::from_secs(5), |_, _| true);
+        assert_eq!(bins.len(), 1);
+        assert_eq!(bins[0].1.duration, 6_000_000_000);
+        assert_eq!(bins[0].1.rate(), 1.0);
+
+        // And their counts can outgrow 32 bits.
+        let counts = |c: u64| Sample { time: c, count: c << 31, overlight: false };
+        let windows = [((), counts(0).until(&counts(2))), ((), counts(2).until(&counts(4)))];
+        let bins = rebin(&windows, Duration::from_secs(1), |_, _| true);
+        assert_eq!(bins[0].1.counter, 1 << 33);
+    }
 }
@@ -2,6 +2,11 @@ use ndarray::{Array1, Array2};
 use std::error::Error;
 use linfa::prelude::*;
 use linfa_elasticnet::ElasticNet;
+use crate::api::MaskSeq;
+use crate::mask::Mask;
+use crate::transform::{LinearOperator, Synthesis};
+
+pub use crate::transform::Basis;
 
 // A x = y
 
@@ -12,7 +17,106 @@ use linfa_elasticnet::ElasticNet;
 // minimize ||Ax - y||_2 + lambda * ||Bx||_1
 // minimize ||(A * B^-1)x - y||_2 + lambda * ||x||_1
 
+/// The measurement matrix of a mask sequence, one row per displayed mask in
+/// `indices`. Rows are kept as the 8-bit masks themselves rather than as a
+/// dense `f64` matrix, which would be eight times larger.
+pub struct MaskOperator {
+    resolution: usize,
+    patterns: Vec<Mask>,
+}
+
+impl MaskOperator {
+    pub fn new(masks: &MaskSeq, indices: Vec<usize>) -> Self {
+        MaskOperator {
+            resolution: masks.resolution(),
+            patterns: indices.iter().map(|i| masks.pattern(*i)).collect(),
+        }
+    }
+}
+
+impl LinearOperator for MaskOperator {
+    fn rows(&self) -> usize {
+        self.patterns.len()
+    }
+
+    fn cols(&self) -> usize {
+        self.resolution * self.resolution
+    }
+
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64> {
+        self.patterns.iter().map(|pattern| {
+            pattern.as_raw().iter().zip(x.iter())
+                .filter(|(p, _)| **p != 0)
+                .map(|(p, v)| (*p as f64) / 255.0 * v)
+                .sum()
+        }).collect()
+    }
+
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64> {
+        let mut result = Array1::<f64>::zeros(self.cols());
+        for (pattern, weight) in self.patterns.iter().zip(y.iter()) {
+            for (target, p) in result.iter_mut().zip(pattern.as_raw()) {
+                if *p != 0 {
+                    *target += (*p as f64) / 255.0 * weight;
+                }
+            }
+        }
+        result
+    }
+}
+
+// A subset of another operator's rows, for cross-validation folds.
+struct RowSubset<'a> {
+    operator: &'a dyn LinearOperator,
+    indices: &'a [usize],
+}
+
+impl<'a> LinearOperator for RowSubset<'a> {
+    fn rows(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn cols(&self) -> usize {
+        self.operator.cols()
+    }
+
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64> {
+        let full = self.operator.apply(x);
+        self.indices.iter().map(|i| full[*i]).collect()
+    }
+
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64> {
+        let mut full = Array1::<f64>::zeros(self.operator.rows());
+        for (value, i) in y.iter().zip(self.indices) {
+            full[*i] = *value;
+        }
+        self.operator.adjoint(&full)
+    }
+}
+
+/// The composition `AB`, applied right to left.
+pub struct Product<'a> {
+    pub left: &'a dyn LinearOperator,
+    pub right: &'a dyn LinearOperator,
+}
 
+impl<'a> LinearOperator for Product<'a> {
+    fn rows(&self) -> usize {
+        self.left.rows()
+    }
+
+    fn cols(&self) -> usize {
+        self.right.cols()
+    }
+
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64> {
+        self.left.apply(&self.right.apply(x))
+    }
+
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64> {
+        self.right.adjoint(&self.left.adjoint(y))
+    }
+}
 
 pub fn dct_1d(vec: &Array1<f64>) -> Array1<f64> {
     // y_k = sum_0^(n - 1) x_n cos((pi / N) * (n + 0.5) * k)
@@ -29,32 +133,227 @@ pub fn dct_1d(vec: &Array1<f64>) -> Array1<f64> {
     result
 }
 
-// pub fn idct_1D(vec: &Array1<f64>) -> Array1<f64> {
-//     // y_k = (x_0 / sqrt(2) + sum_1^(n - 1) x_n cos((pi / N) * (k + 0.5) * n))
-//     //       * sqrt(2 / N)
-//     let size = vec.shape()[0];
-//     let mut result = Array1::<f64>::zeros(size);
-//     for k in 0 .. size {
-//         let mut value = vec[0] / 2.0;
-//         for n in 1 .. size {
-//             value += vec[n] * f64::cos((std::f64::consts::PI / (size as f64))
-//                                        * ((k as f64) + 0.5) * (n as f64));
-//         }
-//         result[k] = value;
-//     }
-//     result
-// }
+fn soft_threshold(value: f64, threshold: f64) -> f64 {
+    value.signum() * f64::max(value.abs() - threshold, 0.0)
+}
 
-// pub fn dct_matrix(dimension: usize) -> Array2<f64> {
-//     let mut result = Array2::<f64>::zeros((dimension, dimension));
-//     let sqrt_dimension = f64::sqrt(dimension as f64);
-//     for ((i, j), element) in result.indexed_iter_mut() {
-//         if i == 0 {
-//             *element = omega.powi((i * j) % dimension) / sqrt_dimension;
-//         }
-//     }
-//     result
-// }
+/// Largest eigenvalue of AᵀA by power iteration, i.e. the Lipschitz constant of
+/// the gradient of ½‖Ax − y‖₂².
+pub fn spectral_norm_squared(a: &dyn LinearOperator, iterations: usize) -> f64 {
+    let mut v = Array1::<f64>::ones(a.cols());
+    v /= f64::sqrt(a.cols() as f64);
+    let mut norm = 0.0;
+    for _ in 0 .. iterations {
+        let w = a.adjoint(&a.apply(&v));
+        norm = w.dot(&w).sqrt();
+        if norm == 0.0 {
+            break;
+        }
+        v = w / norm;
+    }
+    norm
+}
+
+/// Iterative solvers stop early once an iteration changes the solution by
+/// less than this fraction of its norm.
+pub const TOLERANCE: f64 = 1e-6;
+
+/// Minimizes `½‖Ax − y‖₂² + λ‖x‖₁` by fast iterative soft thresholding
+/// (Beck & Teboulle's FISTA).
+pub fn fista(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    lambda: f64,
+    iterations: usize,
+) -> Array1<f64> {
+    fista_from(a, y, lambda, iterations, Array1::zeros(a.cols()))
+}
+
+// FISTA warm started from `x`, for solving along a path of decreasing `λ`.
+fn fista_from(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    lambda: f64,
+    iterations: usize,
+    mut x: Array1<f64>,
+) -> Array1<f64> {
+    // Power iteration approaches the norm from below, so pad it slightly.
+    let lipschitz = 1.01 * spectral_norm_squared(a, 50);
+    if lipschitz == 0.0 {
+        return x;
+    }
+    let step = 1.0 / lipschitz;
+    let mut z = x.clone();
+    let mut t = 1.0;
+    for _ in 0 .. iterations {
+        let gradient = a.adjoint(&(a.apply(&z) - y));
+        let next = (&z - &(gradient * step))
+            .mapv(|v| soft_threshold(v, lambda * step));
+        let next_t = (1.0 + f64::sqrt(1.0 + 4.0 * t * t)) / 2.0;
+        let change = &next - &x;
+        z = &next + &(&change * ((t - 1.0) / next_t));
+        x = next;
+        t = next_t;
+        if change.dot(&change) <= TOLERANCE * TOLERANCE * x.dot(&x) {
+            break;
+        }
+    }
+    x
+}
+
+/// Minimizes `½‖Ax − y‖₂² + λ‖x‖₁` with `linfa`'s coordinate descent
+/// ElasticNet (pure LASSO when `l1_ratio` is 1). This materializes `A`, so it
+/// only suits small grids.
+pub fn elastic_net(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    lambda: f64,
+    l1_ratio: f64,
+) -> Result<Array1<f64>, Box<dyn Error>> {
+    let mut records = Array2::<f64>::zeros((a.rows(), a.cols()));
+    let mut unit = Array1::<f64>::zeros(a.cols());
+    for j in 0 .. a.cols() {
+        unit[j] = 1.0;
+        records.column_mut(j).assign(&a.apply(&unit));
+        unit[j] = 0.0;
+    }
+    let train = linfa::Dataset::new(records, y.clone());
+    // linfa divides the squared error by the number of samples.
+    let model = ElasticNet::<f64>::params()
+        .penalty(lambda / (a.rows() as f64))
+        .l1_ratio(l1_ratio)
+        .with_intercept(false)
+        .fit(&train)?;
+    Ok(model.hyperplane().clone())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Solver {
+    Fista { iterations: usize },
+    ElasticNet { l1_ratio: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub basis: Basis,
+    pub solver: Solver,
+    /// Weight of the L1 term; `None` picks one by cross-validation.
+    pub lambda: Option<f64>,
+    /// Number of folds and of candidate weights tried by cross-validation.
+    pub folds: usize,
+    pub candidates: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            basis: Basis::Pixel,
+            solver: Solver::Fista { iterations: 500 },
+            lambda: None,
+            folds: 5,
+            candidates: 8,
+        }
+    }
+}
+
+fn solve(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    lambda: f64,
+    solver: Solver,
+    initial: Option<Array1<f64>>,
+) -> Array1<f64> {
+    match solver {
+        Solver::Fista { iterations } => {
+            let initial = initial.unwrap_or_else(|| Array1::zeros(a.cols()));
+            fista_from(a, y, lambda, iterations, initial)
+        },
+        Solver::ElasticNet { l1_ratio } => {
+            elastic_net(a, y, lambda, l1_ratio).unwrap()
+        },
+    }
+}
+
+/// Smallest `λ` for which the solution is identically zero, `‖Aᵀy‖_∞`.
+pub fn lambda_max(a: &dyn LinearOperator, y: &Array1<f64>) -> f64 {
+    a.adjoint(y).fold(0.0, |m, v| f64::max(m, v.abs()))
+}
+
+/// `candidates` weights spaced logarithmically over three decades below
+/// `largest`.
+pub fn lambda_grid(largest: f64, candidates: usize) -> Vec<f64> {
+    (1 ..= candidates)
+        .map(|i| largest * f64::powf(10.0, -3.0 * (i as f64) / (candidates as f64)))
+        .collect()
+}
+
+/// Picks the weight from `grid` whose solutions best predict held-out
+/// measurements under K-fold cross-validation. `solve` is called for each
+/// weight in order, warm started from its solution for the previous one, so
+/// `grid` should be decreasing.
+pub fn cross_validate_with(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    grid: &[f64],
+    folds: usize,
+    solve: impl Fn(&dyn LinearOperator, &Array1<f64>, f64, Option<Array1<f64>>)
+                   -> Array1<f64>,
+) -> f64 {
+    let folds = usize::max(2, usize::min(folds, a.rows()));
+    let mut errors = vec![0.0; grid.len()];
+    for fold in 0 .. folds {
+        let (test, train): (Vec<usize>, Vec<usize>) =
+            (0 .. a.rows()).partition(|i| i % folds == fold);
+        let train_op = RowSubset { operator: a, indices: &train };
+        let test_op = RowSubset { operator: a, indices: &test };
+        let train_y: Array1<f64> = train.iter().map(|i| y[*i]).collect();
+        let test_y: Array1<f64> = test.iter().map(|i| y[*i]).collect();
+        let mut previous = None;
+        for (error, lambda) in errors.iter_mut().zip(grid) {
+            let x = solve(&train_op, &train_y, *lambda, previous);
+            let residual = test_op.apply(&x) - &test_y;
+            *error += residual.dot(&residual);
+            previous = Some(x);
+        }
+    }
+    let best = (0 .. grid.len())
+        .min_by(|i, j| errors[*i].partial_cmp(&errors[*j]).unwrap())
+        .unwrap();
+    grid[best]
+}
+
+/// Picks `λ` for the L1 problem from a grid below `lambda_max` by K-fold
+/// cross-validation.
+pub fn cross_validate(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    solver: Solver,
+    folds: usize,
+    candidates: usize,
+) -> f64 {
+    let grid = lambda_grid(lambda_max(a, y), candidates);
+    cross_validate_with(a, y, &grid, folds, |a, y, lambda, initial| {
+        solve(a, y, lambda, solver, initial)
+    })
+}
+
+/// Recovers an `n × n` image (flattened row-major) from `y = Ax`, assuming it
+/// is sparse in `options.basis`.
+pub fn recover(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    n: usize,
+    options: &Options,
+) -> Array1<f64> {
+    let basis = Synthesis { basis: options.basis, resolution: n };
+    let synthesis = Product { left: a, right: &basis };
+    let lambda = options.lambda.unwrap_or_else(|| {
+        cross_validate(&synthesis, y, options.solver,
+                       options.folds, options.candidates)
+    });
+    let coefficients = solve(&synthesis, y, lambda, options.solver, None);
+    options.basis.synthesize(n, &coefficients)
+}
 
 // Dataset<f64, f64>
 pub fn main() -> Result<(), Box<dyn Error>> {
@@ -127,3 +426,84 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 //                      vec![-0.8, 0.5]).unwrap()).unwrap());
 //     Ok(())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    const N: usize = 16;
+
+    // Symmetric Bernoulli sensing matrix.
+    fn sensing_matrix(rows: usize, seed: u64) -> Array2<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        Array2::from_shape_fn((rows, N * N), |_| {
+            if rng.gen() { 1.0 } else { -1.0 }
+        })
+    }
+
+    fn sparse_coefficients(seed: u64) -> Array1<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut c = Array1::<f64>::zeros(N * N);
+        for _ in 0 .. 6 {
+            c[rng.gen_range(0 .. N * N)] = rng.gen_range(5.0 .. 10.0);
+        }
+        c
+    }
+
+    fn relative_error(estimate: &Array1<f64>, truth: &Array1<f64>) -> f64 {
+        let difference = estimate - truth;
+        f64::sqrt(difference.dot(&difference) / truth.dot(truth))
+    }
+
+    fn check_recovery(options: Options) {
+        let a = sensing_matrix(128, 1);
+        let truth = options.basis.synthesize(N, &sparse_coefficients(2));
+        let y = a.apply(&truth);
+        let image = recover(&a, &y, N, &options);
+        let error = relative_error(&image, &truth);
+        assert!(error < 0.05, "{:?} error {}", options.basis, error);
+    }
+
+    fn fixed(basis: Basis) -> Options {
+        Options {
+            basis,
+            solver: Solver::Fista { iterations: 2000 },
+            lambda: Some(1.0),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn recovers_pixel_sparse_image() {
+        check_recovery(fixed(Basis::Pixel));
+    }
+
+    #[test]
+    fn recovers_dct_sparse_image() {
+        check_recovery(fixed(Basis::Dct));
+    }
+
+    #[test]
+    fn recovers_haar_sparse_image() {
+        check_recovery(fixed(Basis::Haar));
+    }
+
+    #[test]
+    fn cross_validation_recovers_sparse_image() {
+        check_recovery(Options { folds: 3, candidates: 4, ..Options::default() });
+    }
+
+    #[test]
+    fn mask_operator_adjoint() {
+        let op = MaskOperator::new(
+            &MaskSeq::Random { resolution: 8, count: 20, seed: 4 },
+            (0 .. 20).collect());
+        let x = Array1::from_shape_fn(64, |i| (i % 5) as f64);
+        let y = Array1::from_shape_fn(20, |i| (i % 3) as f64 - 1.0);
+        let lhs = op.apply(&x).dot(&y);
+        let rhs = x.dot(&op.adjoint(&y));
+        assert!((lhs - rhs).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,186 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::edid::DetailedTiming;
+use crate::gpio::Reading;
+use crate::scanline::{Frame, ScanLine};
+
+/// Windows are averaged over bins of this many scanlines before fitting.
+const BIN_LINES: f64 = 8.0;
+
+/// Response of the panel and detector to full-screen black/white steps.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    /// From the flip that starts a step to when the count starts changing,
+    /// in scanlines.
+    pub lines: f64,
+    /// The same in microseconds.
+    pub micros: f64,
+    /// Time constants of the black to white and white to black transitions,
+    /// in microseconds.
+    pub rise: f64,
+    pub fall: f64,
+    /// Count rates with the panel black and white, in counts per second.
+    pub dark: f64,
+    pub bright: f64,
+    /// Number of steps fitted.
+    pub steps: usize,
+}
+
+/// Exponential approach from `from` to `to`, starting `delay` after a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepFit {
+    pub from: f64,
+    pub to: f64,
+    pub delay: f64,
+    pub tau: f64,
+}
+
+impl StepFit {
+    pub fn value(&self, t: f64) -> f64 {
+        if t < self.delay {
+            self.from
+        } else {
+            self.to + (self.from - self.to) * (-(t - self.delay) / self.tau).exp()
+        }
+    }
+
+    fn error(&self, samples: &[(f64, f64)]) -> f64 {
+        samples.iter().map(|(t, v)| (v - self.value(*t)).powi(2)).sum()
+    }
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (n, sum) = values.fold((0, 0.0), |(n, sum), v| (n + 1, sum + v));
+    (n > 0).then(|| sum / n as f64)
+}
+
+/// Fits a [`StepFit`] to `(time, value)` samples of a step at time 0. The
+/// levels are the means before the step and over the last quarter; the
+/// delay and time constant start from the 10% and 90% crossings and are
+/// refined by least squares.
+pub fn fit_step(samples: &[(f64, f64)]) -> Option<StepFit> {
+    let end = samples.iter().map(|(t, _)| *t).fold(f64::NEG_INFINITY, f64::max);
+    let from = mean(samples.iter().filter(|(t, _)| *t < 0.0).map(|(_, v)| *v))?;
+    let to = mean(samples.iter().filter(|(t, _)| *t >= 0.75 * end).map(|(_, v)| *v))?;
+    if end <= 0.0 || from == to {
+        return None;
+    }
+    let crossing = |fraction: f64| {
+        samples.iter()
+            .filter(|(t, _)| *t >= 0.0)
+            .find(|(_, v)| (v - from) / (to - from) >= fraction)
+            .map(|(t, _)| *t)
+    };
+    let (t10, t90) = (crossing(0.1)?, crossing(0.9)?);
+    let tau = ((t90 - t10) / 9f64.ln()).max(BIN_LINES / 4.0);
+    let delay = (t10 - tau * (1.0 / 0.9f64).ln()).max(0.0);
+
+    const STEPS: usize = 32;
+    let mut best = StepFit { from, to, delay, tau };
+    let mut best_error = best.error(samples);
+    for i in 0 ..= STEPS {
+        let delay = (delay + tau * (2.0 * i as f64 / STEPS as f64 - 1.0)).max(0.0);
+        for j in 0 ..= STEPS {
+            let tau = tau * 4f64.powf(j as f64 / STEPS as f64 - 0.5);
+            let fit = StepFit { from, to, delay, tau };
+            let error = fit.error(samples);
+            if error < best_error {
+                best = fit;
+                best_error = error;
+            }
+        }
+    }
+    Some(best)
+}
+
+/// Fits the response to each step in `steps`, given as the flip that put
+/// the new level on screen and whether it was white, using the frame before
+/// it and the `span` frames from it on. `pulses` are tagged with flip
+/// indices as in a capture.
+pub fn analyze(
+    pulses: &[((Frame, ScanLine), Reading)],
+    steps: &[(Frame, bool)],
+    span: u32,
+    timing: &DetailedTiming,
+) -> Option<Latency> {
+    let v_total = timing.v_total() as f64;
+    let mut rising = Vec::new();
+    let mut falling = Vec::new();
+    for &(flip, white) in steps {
+        let mut bins: BTreeMap<i64, (f64, usize)> = BTreeMap::new();
+        for ((frame, line), reading) in pulses {
+            let t = (*frame as f64 - flip as f64) * v_total + *line as f64;
+            if t < -v_total || t >= span as f64 * v_total {
+                continue;
+            }
+            let bin = bins.entry((t / BIN_LINES).floor() as i64).or_default();
+            bin.0 += reading.rate();
+            bin.1 += 1;
+        }
+        let samples: Vec<(f64, f64)> = bins.into_iter()
+            .map(|(bin, (sum, n))| ((bin as f64 + 0.5) * BIN_LINES, sum / n as f64))
+            .collect();
+        if let Some(fit) = fit_step(&samples) {
+            if white { rising.push(fit) } else { falling.push(fit) }
+        }
+    }
+
+    let line = timing.line_duration().as_secs_f64() * 1e6;
+    let lines = mean(rising.iter().chain(&falling).map(|fit| fit.delay))?;
+    Some(Latency {
+        lines,
+        micros: lines * line,
+        rise: mean(rising.iter().map(|fit| fit.tau))? * line,
+        fall: mean(falling.iter().map(|fit| fit.tau))? * line,
+        dark: mean(rising.iter().map(|fit| fit.from))?,
+        bright: mean(rising.iter().map(|fit| fit.to))?,
+        steps: rising.len() + falling.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edid::Edid;
+
+    #[test]
+    fn recovers_simulated_step_response() {
+        let timing = *Edid::modulator().preferred().unwrap();
+        let v_total = timing.v_total();
+        let (delay, rise, fall) = (1800.0, 300.0, 600.0);
+        let span = 6;
+        let steps: Vec<(Frame, bool)> =
+            (1 .. 5).map(|i| (i * span, i % 2 == 1)).collect();
+
+        // A window every 4 lines, with a little deterministic noise.
+        let mut pulses = Vec::new();
+        for frame in 0 .. 5 * span {
+            for line in (0 .. v_total).step_by(4) {
+                let t = (frame * v_total + line) as f64;
+                let mut level = 100.0;
+                for &(flip, white) in &steps {
+                    let since = t - (flip * v_total) as f64 - delay;
+                    if since >= 0.0 {
+                        let (target, tau) = if white { (1100.0, rise) } else { (100.0, fall) };
+                        level = target + (level - target) * (-since / tau).exp();
+                    }
+                }
+                let noise = ((line * 7919 + frame * 104729) % 11) as f64 - 5.0;
+                pulses.push(((frame, line), Reading {
+                    overlight: false,
+                    counter: (level + noise).round() as u64,
+                    start: t as u64,
+                    duration: 100_000,
+                }));
+            }
+        }
+
+        let latency = analyze(&pulses, &steps, span, &timing).unwrap();
+        let line = timing.line_duration().as_secs_f64() * 1e6;
+        assert_eq!(latency.steps, 4);
+        assert!((latency.lines - delay).abs() < 2.0 * BIN_LINES, "{:?}", latency);
+        assert!((latency.rise / line - rise).abs() < 0.1 * rise, "{:?}", latency);
+        assert!((latency.fall / line - fall).abs() < 0.1 * fall, "{:?}", latency);
+        assert!((latency.dark - 1e6).abs() < 5e4 && (latency.bright - 1.1e7).abs() < 5e4);
+    }
+}
@@ -1,23 +1,59 @@
 use std::error::Error;
 use std::collections::BTreeSet;
+use std::time::Duration;
 use drm::Device;
 use drm::control::Device as ControlDevice;
-use drm::control::{self, crtc, framebuffer};
-use drm::control::connector::{Interface, State};
-use drm::control::dumbbuffer::DumbMapping;
+use drm::control::{self, connector, crtc, framebuffer};
+use drm::control::connector::State;
+use drm::control::dumbbuffer::{DumbBuffer, DumbMapping};
 use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use crate::display::{ConnectorInfo, DisplayConfig, DisplayError, ModeInfo};
+use crate::edid::{DetailedTiming, Edid, EdidError};
+use serde_derive::{Deserialize, Serialize};
 //use gbm::{BufferObjectFlags, Format};
 
-pub const RESOLUTIONS: [(usize, usize); 3] = [
-    (1440, 2560),
-    (2560, 1440),
-    (1920, 1080),
-];
+/// Native mode of the modulator panel, used unless configured otherwise.
+pub const DISPLAY_WIDTH: usize = 1440;
+pub const DISPLAY_HEIGHT: usize = 2560;
 
-pub const RESOLUTION_INDEX: usize = 0;
+static CONFIG: Lazy<Mutex<DisplayConfig>> =
+    Lazy::new(|| Mutex::new(DisplayConfig::default()));
 
-pub const DISPLAY_WIDTH: usize = RESOLUTIONS[RESOLUTION_INDEX].0;
-pub const DISPLAY_HEIGHT: usize = RESOLUTIONS[RESOLUTION_INDEX].1;
+/// The display the next call to [`run`] will drive.
+pub fn config() -> DisplayConfig {
+    CONFIG.lock().unwrap().clone()
+}
+
+pub fn configure(config: DisplayConfig) {
+    info!("Display configured as {} on {}", config, config.card);
+    *CONFIG.lock().unwrap() = config;
+}
+
+static TIMING: Lazy<Mutex<Option<DetailedTiming>>> = Lazy::new(|| Mutex::new(None));
+
+/// Timing of the mode last displayed, for converting scanlines to times.
+pub fn timing() -> Option<DetailedTiming> {
+    *TIMING.lock().unwrap()
+}
+
+/// Timing of the mode last displayed or, before anything has been, of the
+/// modulator mode the configuration asks for, so that durations can be
+/// turned into frames ahead of a capture.
+pub fn expected_timing() -> Option<DetailedTiming> {
+    timing().or_else(|| {
+        let config = config();
+        Edid::modulator().timings.into_iter()
+            .filter(|t| t.size() == config.size)
+            .filter(|t| config.refresh.map_or(true, |hz| t.refresh_rate().round() as u32 == hz))
+            .max_by(|a, b| a.refresh_rate().total_cmp(&b.refresh_rate()))
+    })
+}
+
+// Connectors can take a moment to report a panel after boot or a mode change.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 struct Card(std::fs::File);
 
@@ -32,72 +68,196 @@ impl drm::Device for Card {}
 impl drm::control::Device for Card {}
 
 impl Card {
-    pub fn open(path: &str) -> Self {
+    pub fn open(path: &str) -> std::io::Result<Self> {
         let mut options = std::fs::OpenOptions::new();
         options.read(true);
         options.write(true);
-        Card(options.open(path).unwrap())
+        Ok(Card(options.open(path)?))
     }
 }
 
-pub fn run(
-    mut render_callback: impl for<'a> FnMut(&mut DumbMapping<'a>) -> bool
-) -> Result<(), Box<dyn Error>> {
-    let card_device = "/dev/dri/card0";
+/// What the display is doing when the render callback runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameInfo {
+    /// DRM vblank sequence number of the page flip that just completed.
+    pub sequence: u32,
+    /// When that flip completed, on the monotonic clock.
+    pub timestamp: Duration,
+    /// Buffer that flip put on screen.
+    pub displayed: usize,
+    /// Buffer the callback draws into, shown from the next flip on.
+    pub rendering: usize,
+    /// Vblanks skipped since the previous flip, during which the previous
+    /// frame stayed on screen; nonzero when a callback was late.
+    pub missed: u32,
+}
+
+// An opened display, ready to show framebuffers.
+struct Output {
+    drm: Card,
+    connector: drm::control::connector::Handle,
+    mode: drm::control::Mode,
+    crtc: crtc::Handle,
+}
 
-    debug!("Opening {}", card_device);
+fn mode_info(mode: &drm::control::Mode) -> ModeInfo {
+    ModeInfo {
+        name: mode.name().to_string_lossy().into_owned(),
+        size: mode.size(),
+        refresh: mode.vrefresh(),
+        preferred: mode.mode_type().contains(drm::control::ModeTypeFlags::PREFERRED),
+    }
+}
 
-    let drm = Card::open(card_device);
+// Timing of a mode as the kernel reports it, lacking the physical size.
+fn mode_timing(mode: &drm::control::Mode) -> DetailedTiming {
+    let (h_active, v_active) = mode.size();
+    let (h_sync_start, h_sync_end, h_total) = mode.hsync();
+    let (v_sync_start, v_sync_end, v_total) = mode.vsync();
+    DetailedTiming {
+        pixel_clock: mode.clock(),
+        h_active,
+        h_blank: h_total - h_active,
+        v_active,
+        v_blank: v_total - v_active,
+        h_sync_offset: h_sync_start - h_active,
+        h_sync_width: h_sync_end - h_sync_start,
+        v_sync_offset: v_sync_start - v_active,
+        v_sync_width: v_sync_end - v_sync_start,
+        size_mm: (0, 0),
+        interlaced: mode.flags().contains(drm::control::ModeFlags::INTERLACE),
+    }
+}
+
+// Reads the EDID blob the kernel attached to `connector`, if any.
+fn read_edid(drm: &Card, connector: connector::Handle)
+             -> Result<Option<Vec<u8>>, Box<dyn Error>>
+{
+    let properties = drm.get_properties(connector)?;
+    let (handles, values) = properties.as_props_and_values();
+    for (handle, value) in handles.iter().zip(values) {
+        let info = drm.get_property(*handle)?;
+        if info.name().to_str()? == "EDID" && *value != 0 {
+            return Ok(Some(drm.get_property_blob(*value)?));
+        }
+    }
+    Ok(None)
+}
+
+// Describes every connector of `drm`, alongside its handle and modes.
+fn probe(drm: &Card)
+         -> Result<Vec<(connector::Handle, Vec<drm::control::Mode>, ConnectorInfo)>,
+                   Box<dyn Error>>
+{
+    let mut connectors = Vec::new();
+    for handle in drm.resource_handles()?.connectors() {
+        let info = drm.get_connector(*handle, false)?;
+        trace!("Connector {:?} modes: {:?}", handle, info.modes());
+        let name = format!("{:?}-{}", info.interface(), info.interface_id());
+        let edid = match read_edid(drm, *handle)? {
+            Some(bytes) => Edid::parse(&bytes)
+                .map_err(|e| warn!("Ignoring EDID of {}: {}", name, e))
+                .ok(),
+            None => None,
+        };
+        connectors.push((*handle, info.modes().to_vec(), ConnectorInfo {
+            name,
+            connected: info.state() == State::Connected,
+            modes: info.modes().iter().map(mode_info).collect(),
+            edid,
+        }));
+    }
+    Ok(connectors)
+}
+
+/// Connectors of `card` and the modes they offer.
+pub fn connectors(card: &str) -> Result<Vec<ConnectorInfo>, Box<dyn Error>> {
+    let drm = Card::open(card)?;
+    Ok(probe(&drm)?.into_iter().map(|(_, _, info)| info).collect())
+}
+
+// Opens the configured card, takes the master lock, and picks the
+// connector, mode and crtc to display on.
+fn open(config: &DisplayConfig) -> Result<Output, Box<dyn Error>> {
+    debug!("Opening {}", config.card);
+
+    let drm = Card::open(&config.card)?;
 
     // let drm = gbm::Device::new(card)?;
 
     debug!("Acquiring libdrm master lock");
     drm.acquire_master_lock()?;
 
-    let resource_handles = drm.resource_handles()?;
-
-    for connector in resource_handles.connectors() {
-        let info = drm.get_connector(connector.clone(), false).unwrap();
-        trace!("Connector modes: {:?}", info.modes());
-    }
-
-    debug!("Finding appropriate connector and mode");
-
-    let mut chosen_connector: Option<drm::control::connector::Handle> = None;
-    let mut chosen_mode: Option<drm::control::Mode> = None;
-    'outer1: for _ in 0..100000 {
-        for connector in resource_handles.connectors() {
-            let info = drm.get_connector(connector.clone(), false)?;
-            if info.interface() == Interface::HDMIA {
-                if info.state() == State::Connected {
-                    let sizes: Vec<(u16, u16)> =
-                        info.modes().iter().map(|m| m.size()).collect();
-                    if let Some(mode_index) = sizes.iter().position(|s| s == &(DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16)) {
-                        chosen_connector = Some(connector.clone());
-                        chosen_mode = Some(info.modes()[mode_index]);
-                        break 'outer1;
-                    }
+    debug!("Finding connector and mode for {}", config);
+
+    let started = std::time::Instant::now();
+    let (chosen_connector, chosen_mode, chosen_info) = loop {
+        let connectors = probe(&drm)?;
+        let infos: Vec<ConnectorInfo> =
+            connectors.iter().map(|(_, _, info)| info.clone()).collect();
+        match config.choose(&infos) {
+            Ok((c, m)) => {
+                let (handle, modes, info) = &connectors[c];
+                break (*handle, modes[m], info.clone());
+            },
+            Err(e) if started.elapsed() >= CONNECT_TIMEOUT => {
+                drm.release_master_lock()?;
+                return Err(Box::new(e));
+            },
+            Err(_) => std::thread::sleep(CONNECT_POLL_INTERVAL),
+        }
+    };
+
+    let name = chosen_info.name;
+
+    // Timings from the EDID carry the panel size, so prefer them.
+    let mut timing = mode_timing(&chosen_mode);
+    match &chosen_info.edid {
+        Some(edid) => {
+            if config.check_edid {
+                if let Err(e) = edid.check(&Edid::modulator()) {
+                    drm.release_master_lock()?;
+                    return Err(Box::new(e));
                 }
             }
-        }
+            let same = |t: &&DetailedTiming| {
+                (t.pixel_clock, t.h_total(), t.v_total())
+                    == (timing.pixel_clock, timing.h_total(), timing.v_total())
+            };
+            if let Some(edid_timing) = edid.timing(chosen_mode.size()).filter(same) {
+                timing = *edid_timing;
+            }
+        },
+        None if config.check_edid => {
+            drm.release_master_lock()?;
+            return Err(Box::new(EdidError::Missing));
+        },
+        None => {},
     }
-    let chosen_connector = chosen_connector.unwrap();
-    let chosen_mode = chosen_mode.unwrap();
+    *TIMING.lock().unwrap() = Some(timing);
 
-    trace!("connector = {:?}", drm.get_connector(chosen_connector.clone(), false)?);
+    trace!("connector = {:?}", drm.get_connector(chosen_connector, false)?);
     trace!("mode      = {:?}", chosen_mode);
 
     debug!("Finding appropriate crtc");
+    let resource_handles = drm.resource_handles()?;
     let mut chosen_crtc = None;
-    'outer2: for encoder in drm.get_connector(chosen_connector.clone(), false)?.encoders() {
+    'outer2: for encoder in drm.get_connector(chosen_connector, false)?.encoders() {
         let encoder_info = drm.get_encoder(encoder.clone())?;
         for crtc in resource_handles.filter_crtcs(encoder_info.possible_crtcs()) {
             chosen_crtc = Some(crtc.clone());
             break 'outer2;
         }
     }
-    let chosen_crtc = chosen_crtc.unwrap();
-
+    let chosen_crtc = match chosen_crtc {
+        Some(crtc) => crtc,
+        None => {
+            drm.release_master_lock()?;
+            return Err(Box::new(DisplayError::NoCrtc(name)));
+        },
+    };
+
+    info!("Displaying {} on {}", mode_info(&chosen_mode), name);
     trace!("crtc      = {:?}", drm.get_crtc(chosen_crtc.clone())?);
 
     for prop in drm.get_properties(chosen_crtc.clone())?.as_props_and_values().0 {
@@ -117,80 +277,240 @@ pub fn run(
         trace!("connector property: name = {}, {:?}", name, info);
     }
 
-    debug!("Creating dumb buffers");
+    Ok(Output {
+        drm,
+        connector: chosen_connector,
+        mode: chosen_mode,
+        crtc: chosen_crtc,
+    })
+}
 
-    // drm::buffer::DrmFourcc::Argb8888
-    let mut bo1 = drm.create_dumb_buffer(
-        (DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32),
-        drm::buffer::DrmFourcc::Rgb888, 24)?;
-    let mut bo2 = drm.create_dumb_buffer(
-        (DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32),
-        drm::buffer::DrmFourcc::Rgb888, 24)?;
+impl Output {
+    // Creates a framebuffer the size of the mode.
+    fn create_buffer(&self)
+                     -> Result<(DumbBuffer, framebuffer::Handle), Box<dyn Error>>
+    {
+        let (width, height) = self.mode.size();
+        // drm::buffer::DrmFourcc::Argb8888
+        let buffer = self.drm.create_dumb_buffer(
+            (width as u32, height as u32),
+            drm::buffer::DrmFourcc::Rgb888, 24)?;
+        let handle = self.drm.add_framebuffer(&buffer, 24, 24)?;
+        Ok((buffer, handle))
+    }
 
-    debug!("Adding dumb buffers as framebuffers");
+    // Shows `first` and queues a flip to it, whose event starts the loop.
+    fn start(&self, first: framebuffer::Handle) -> Result<(), Box<dyn Error>> {
+        debug!("Setting the crtc to the first buffer and chosen connector/mode");
 
-    let mut fb1 = drm.add_framebuffer(&bo1, 24, 24)?;
-    let mut fb2 = drm.add_framebuffer(&bo2, 24, 24)?;
+        self.drm.set_crtc(self.crtc,
+                          Some(first), (0, 0),
+                          &[self.connector],
+                          Some(self.mode))?;
 
-    debug!("Mapping dumb buffers");
+        debug!("Initial page_flip to pump the event loop");
 
-    let mut bm1 = drm.map_dumb_buffer(&mut bo1)?;
-    let mut bm2 = drm.map_dumb_buffer(&mut bo2)?;
+        self.flip(first)
+    }
 
-    debug!("Filling dumb buffers with 0x0");
+    fn flip(&self, buffer: framebuffer::Handle) -> Result<(), Box<dyn Error>> {
+        self.drm.page_flip(self.crtc,
+                           buffer,
+                           drm::control::PageFlipFlags::EVENT,
+                           None)?;
+        Ok(())
+    }
 
-    bm1.as_mut().fill(0);
-    bm2.as_mut().fill(0);
-
-    let mut front_dm: &mut DumbMapping = &mut bm1;
-    let mut back_dm: &mut DumbMapping = &mut bm2;
-    let mut front_buffer: &mut drm::control::framebuffer::Handle = &mut fb1;
-    let mut back_buffer: &mut drm::control::framebuffer::Handle = &mut fb2;
-
-    debug!("Setting the crtc to the front buffer and chosen connector/mode");
-
-    drm.set_crtc(chosen_crtc,
-                 Some(*front_buffer), (0, 0),
-                 &[chosen_connector],
-                 Some(chosen_mode))?;
-
-    debug!("Initial page_flip to pump the event loop");
-
-    drm.page_flip(chosen_crtc,
-                  *front_buffer,
-                  drm::control::PageFlipFlags::EVENT,
-                  None)?;
-
-    debug!("Starting libdrm event loop");
-
-    'event_loop: loop {
-        for event in drm.receive_events()? {
-            match event {
-                drm::control::Event::PageFlip(page_flip) => {
-                    // debug!("Received page flip: frame {:?}, duration {:?}, crtc {:?}",
-                    //          page_flip.frame, page_flip.duration, page_flip.crtc);
-                    if page_flip.crtc == chosen_crtc {
-                        std::mem::swap(&mut front_dm, &mut back_dm);
-                        std::mem::swap(&mut front_buffer, &mut back_buffer);
-                        drm.page_flip(
-                            chosen_crtc,
-                            *front_buffer,
-                            drm::control::PageFlipFlags::EVENT,
-                            None)?;
-                        if render_callback(back_dm) {
-                            debug!("Render callback returned `true`, ending event loop");
-                            break 'event_loop;
+    // Calls `on_flip` with the sequence number, timestamp and missed vblanks
+    // of each page flip until it returns `false`.
+    fn event_loop(
+        &self,
+        mut on_flip: impl FnMut(u32, Duration, u32) -> Result<bool, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!("Starting libdrm event loop");
+
+        let mut previous_sequence: Option<u32> = None;
+        'event_loop: loop {
+            for event in self.drm.receive_events()? {
+                match event {
+                    drm::control::Event::PageFlip(page_flip) => {
+                        // debug!("Received page flip: frame {:?}, duration {:?}, crtc {:?}",
+                        //          page_flip.frame, page_flip.duration, page_flip.crtc);
+                        if page_flip.crtc == self.crtc {
+                            let missed = previous_sequence.map_or(0, |previous| {
+                                page_flip.frame.wrapping_sub(previous).saturating_sub(1)
+                            });
+                            if missed > 0 {
+                                warn!("Missed {} vblank(s) before sequence {}",
+                                      missed, page_flip.frame);
+                            }
+                            previous_sequence = Some(page_flip.frame);
+                            if !on_flip(page_flip.frame, page_flip.duration, missed)? {
+                                break 'event_loop;
+                            }
                         }
-                    }
-                },
-                _ => {},
+                    },
+                    _ => {},
+                }
             }
         }
+        Ok(())
+    }
+
+    fn close(self) -> Result<(), Box<dyn Error>> {
+        debug!("Releasing libdrm master lock");
+
+        self.drm.release_master_lock()?;
+        Ok(())
+    }
+}
+
+/// Calls `render_callback` once per refresh to draw the next frame off
+/// screen, until it returns `true`.
+pub fn run(
+    mut render_callback: impl for<'a> FnMut(&mut DumbMapping<'a>, &FrameInfo) -> bool
+) -> Result<(), Box<dyn Error>> {
+    let output = open(&config())?;
+
+    debug!("Creating dumb buffers");
+
+    let (mut bo1, fb1) = output.create_buffer()?;
+    let (mut bo2, fb2) = output.create_buffer()?;
+
+    debug!("Mapping dumb buffers");
+
+    let bm1 = output.drm.map_dumb_buffer(&mut bo1)?;
+    let bm2 = output.drm.map_dumb_buffer(&mut bo2)?;
+    let mut mappings = [bm1, bm2];
+    let buffers = [fb1, fb2];
+
+    debug!("Filling dumb buffers with 0x0");
+
+    for mapping in mappings.iter_mut() {
+        mapping.as_mut().fill(0);
+    }
+
+    output.start(buffers[0])?;
+
+    // Draw into whichever buffer isn't being scanned out, then queue a flip
+    // to it, so a frame is never modified while it is on screen.
+    let mut displayed = 0;
+    output.event_loop(|sequence, timestamp, missed| {
+        let info = FrameInfo {
+            sequence,
+            timestamp,
+            displayed,
+            rendering: 1 - displayed,
+            missed,
+        };
+        if render_callback(&mut mappings[info.rendering], &info) {
+            debug!("Render callback returned `true`, ending event loop");
+            return Ok(false);
+        }
+        output.flip(buffers[info.rendering])?;
+        displayed = info.rendering;
+        Ok(true)
+    })?;
+
+    output.close()
+}
+
+/// Framebuffers are allocated from the GPU's contiguous memory, which holds
+/// a few dozen of them at 11 MB each if the CMA pool is large enough.
+pub const MAX_PRELOADED: usize = 32;
+
+/// Fewer framebuffers fit in the GPU's contiguous memory than were asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBuffers {
+    pub wanted: usize,
+    /// How many were allocated before it ran out.
+    pub allocated: usize,
+}
+
+impl std::fmt::Display for OutOfBuffers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Only {} of {} framebuffers fit in GPU memory", self.allocated, self.wanted)
+    }
+}
+
+impl Error for OutOfBuffers {}
+
+/// Like [`run`], but draws `count` framebuffers up front with `prepare`, so
+/// that each refresh only flips between them without touching any pixels.
+/// `schedule` picks the buffer to show next, or returns `None` to stop.
+/// Buffer 0 is on screen first; `FrameInfo::rendering` is unused.
+///
+/// All buffers are allocated before any is drawn. If they don't fit, they
+/// are freed again and [`OutOfBuffers`] says how many did, so the caller can
+/// retry with fewer.
+pub fn run_preloaded(
+    count: usize,
+    mut prepare: impl for<'a> FnMut(usize, &mut DumbMapping<'a>),
+    mut schedule: impl FnMut(&FrameInfo) -> Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    assert!((1 ..= MAX_PRELOADED).contains(&count),
+            "Can't preload {} framebuffers", count);
+    let output = open(&config())?;
+
+    debug!("Creating {} dumb buffers", count);
+
+    let mut dumb_buffers = Vec::with_capacity(count);
+    let mut buffers = Vec::with_capacity(count);
+    for _ in 0 .. count {
+        match output.create_buffer() {
+            Ok((buffer, handle)) => {
+                dumb_buffers.push(buffer);
+                buffers.push(handle);
+            },
+            Err(e) => {
+                warn!("Couldn't allocate framebuffer {} of {}: {}", buffers.len() + 1, count, e);
+                let allocated = buffers.len();
+                for (buffer, handle) in dumb_buffers.into_iter().zip(buffers) {
+                    output.drm.destroy_framebuffer(handle)?;
+                    output.drm.destroy_dumb_buffer(buffer)?;
+                }
+                output.close()?;
+                return Err(OutOfBuffers { wanted: count, allocated }.into());
+            },
+        }
     }
 
-    debug!("Releasing libdrm master lock");
+    debug!("Drawing {} dumb buffers", count);
 
-    drm.release_master_lock()?;
+    for (index, buffer) in dumb_buffers.iter_mut().enumerate() {
+        prepare(index, &mut output.drm.map_dumb_buffer(buffer)?);
+    }
+
+    output.start(buffers[0])?;
+
+    let mut displayed = 0;
+    output.event_loop(|sequence, timestamp, missed| {
+        let info = FrameInfo {
+            sequence,
+            timestamp,
+            displayed,
+            rendering: displayed,
+            missed,
+        };
+        match schedule(&info) {
+            Some(next) => {
+                output.flip(buffers[next])?;
+                displayed = next;
+                Ok(true)
+            },
+            None => {
+                debug!("Schedule returned `None`, ending event loop");
+                Ok(false)
+            },
+        }
+    })?;
+
+    // A buffer can't be freed while it's on screen.
+    output.drm.set_crtc(output.crtc, None, (0, 0), &[], None)?;
+    for (buffer, handle) in dumb_buffers.into_iter().zip(buffers) {
+        output.drm.destroy_framebuffer(handle)?;
+        output.drm.destroy_dumb_buffer(buffer)?;
+    }
 
-    Ok(())
+    output.close()
 }
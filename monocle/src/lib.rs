@@ -18,12 +18,23 @@ pub mod server;
 pub mod client;
 pub mod api;
 pub mod lcd;
+pub mod display;
+pub mod edid;
+pub mod gamma;
+pub mod latency;
+pub mod profile;
 pub mod rotation;
 pub mod mask;
+pub mod photometry;
+pub mod sep;
 pub mod quantity;
 pub mod adaptive;
+pub mod aperture;
 pub mod goto;
 pub mod tracking;
+pub mod transform;
+pub mod tv;
+pub mod reconstruct;
 
 //pub fn print_peek(file: &std::fs::File, addr: u32) {
 //    println!("peek({:#x}) == {:#x}", addr, mailbox::peek(file, addr).unwrap());
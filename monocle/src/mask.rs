@@ -1,21 +1,31 @@
+use std::borrow::Borrow;
+use std::ops::Range;
+use crate::api::MaskSeq;
 use crate::quantity::{PixelDistance, RotationAngle};
-use rand::Rng;
+use crate::display::{Addressing, DisplayGeometry};
+use crate::sep::aperture::EllipseAxes;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use image::Luma;
 use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use serde_derive::{Deserialize, Serialize};
 
 pub type Mask = imageproc::definitions::Image<image::Luma<u8>>;
 
 pub fn binary_random_mask<T: Rng>(
+    rng: &mut T,
     width: usize,
     height: usize,
 ) -> Mask {
     let mut result = Mask::new(width as u32, height as u32);
     for pixel in result.pixels_mut() {
-        *pixel = Luma([if rand::random() { 255u8 } else { 0u8 }]);
+        *pixel = Luma([if rng.gen() { 255u8 } else { 0u8 }]);
     }
     result
 }
 
+/// Levels are transmissions; `Pipeline::response` maps them to the bytes
+/// that give them on the panel.
 pub fn grayscale_uniform_random_mask<T: Rng>(
     width: usize,
     height: usize,
@@ -27,6 +37,93 @@ pub fn grayscale_uniform_random_mask<T: Rng>(
     result
 }
 
+impl MaskSeq {
+    /// Side length of the square grid this sequence's masks are drawn on.
+    pub fn resolution(&self) -> usize {
+        match *self {
+            MaskSeq::ScanningBox => {
+                (crate::capture::SCAN_SIZE / crate::capture::DIVIDER) as usize
+            },
+            MaskSeq::Hadamard(n) => n,
+            MaskSeq::Fourier(n) => n,
+            MaskSeq::Random { resolution, .. } => resolution,
+            MaskSeq::AdaptiveQuadtree { resolution, .. } => resolution,
+            MaskSeq::AdaptiveHaar { resolution, .. } => resolution,
+            MaskSeq::Dark { .. } => 1,
+        }
+    }
+
+    /// Number of masks (and therefore frames) in the sequence; an upper bound
+    /// for adaptive sequences, which may stop early.
+    pub fn len(&self) -> usize {
+        let n = self.resolution();
+        match *self {
+            MaskSeq::ScanningBox => n * n,
+            MaskSeq::Hadamard(_) => n * n,
+            // Four phase steps per spatial frequency.
+            MaskSeq::Fourier(_) => 4 * n * n,
+            MaskSeq::Random { count, .. } => count,
+            MaskSeq::AdaptiveQuadtree { budget, .. } => budget,
+            MaskSeq::AdaptiveHaar { budget, .. } => budget,
+            MaskSeq::Dark { frames } => frames,
+        }
+    }
+
+    /// The `index`th mask of the sequence, one pixel per grid cell.
+    ///
+    /// Masks are a pure function of the sequence and index, so reconstruction
+    /// can regenerate them instead of storing them in the capture. Adaptive
+    /// sequences depend on their measurements and are replayed instead; see
+    /// `adaptive::replay`.
+    pub fn pattern(&self, index: usize) -> Mask {
+        let n = self.resolution() as u32;
+        match *self {
+            MaskSeq::ScanningBox => {
+                let (bx, by) = ((index as u32) % n, (index as u32) / n);
+                Mask::from_fn(n, n, |x, y| {
+                    Luma([if (x, y) == (bx, by) { 255 } else { 0 }])
+                })
+            },
+            MaskSeq::Hadamard(_) => {
+                assert!(n.is_power_of_two(),
+                        "Hadamard resolution {} is not a power of two", n);
+                let (u, v) = ((index as u32) / n, (index as u32) % n);
+                Mask::from_fn(n, n, |x, y| {
+                    let parity = (u & y).count_ones() + (v & x).count_ones();
+                    Luma([if parity % 2 == 0 { 255 } else { 0 }])
+                })
+            },
+            MaskSeq::Fourier(_) => {
+                let frequency = (index / 4) as u32;
+                let phase = std::f64::consts::FRAC_PI_2 * ((index % 4) as f64);
+                let (fx, fy) = (frequency % n, frequency / n);
+                Mask::from_fn(n, n, |x, y| {
+                    let theta = 2.0 * std::f64::consts::PI
+                        * ((fx * x + fy * y) % n) as f64 / (n as f64);
+                    let value = 0.5 + 0.5 * f64::cos(theta + phase);
+                    Luma([(255.0 * value).round() as u8])
+                })
+            },
+            MaskSeq::Random { seed, .. } => {
+                let mut rng =
+                    StdRng::seed_from_u64(seed.wrapping_add(index as u64));
+                binary_random_mask(&mut rng, n as usize, n as usize)
+            },
+            MaskSeq::Dark { .. } => Mask::new(1, 1),
+            MaskSeq::AdaptiveQuadtree { .. } | MaskSeq::AdaptiveHaar { .. } => {
+                panic!("{:?} masks depend on the measurements", self)
+            },
+        }
+    }
+}
+
+/// The complement of `mask`, lit wherever it is dark.
+pub fn invert_mask(mask: &Mask) -> Mask {
+    Mask::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([255 - mask.get_pixel(x, y).0[0]])
+    })
+}
+
 pub fn rotate_mask(mask: &Mask, angle: RotationAngle) -> Mask {
     rotate_about_center(mask, angle as f32, Interpolation::Bicubic, Luma([0]))
 }
@@ -46,20 +143,444 @@ pub fn rotate_mask(mask: &Mask, angle: RotationAngle) -> Mask {
 //     }
 // }
 
-pub fn apply_circular_cutoff(mask: &mut Mask, cutoff: PixelDistance) {
-    fn distance_squared(a: (f64, f64), b: (f64, f64)) -> f64 {
-        let delta_x = a.0 - b.0;
-        let delta_y = a.1 - b.1;
-        delta_x * delta_x + delta_y * delta_y
+/// The stop limiting which part of the LCD passes light to the detector, in
+/// pixels about the optical axis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aperture {
+    Open,
+    Circular(PixelDistance),
+    /// Semi-axes `a` and `b`, with `a` at angle `theta` from the x axis.
+    Elliptical { a: PixelDistance, b: PixelDistance, theta: RotationAngle },
+    /// Vertices in order around the boundary.
+    Polygon(Vec<(PixelDistance, PixelDistance)>),
+}
+
+impl Aperture {
+    /// Whether the point `(dx, dy)` from the optical axis is inside the stop.
+    pub fn contains(&self, dx: f64, dy: f64) -> bool {
+        match self {
+            Aperture::Open => true,
+            Aperture::Circular(radius) => dx * dx + dy * dy <= radius * radius,
+            Aperture::Elliptical { a, b, theta } => {
+                let (sin, cos) = theta.sin_cos();
+                let (u, v) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+                (u / a).powi(2) + (v / b).powi(2) <= 1.0
+            },
+            Aperture::Polygon(vertices) => {
+                // Even-odd rule: count edges crossed by a ray towards +x.
+                let mut inside = false;
+                let mut previous = match vertices.last() {
+                    Some(vertex) => *vertex,
+                    None => return false,
+                };
+                for &(x, y) in vertices {
+                    if (y > dy) != (previous.1 > dy)
+                        && dx < x + (dy - y) * (previous.0 - x) / (previous.1 - y)
+                    {
+                        inside = !inside;
+                    }
+                    previous = (x, y);
+                }
+                inside
+            },
+        }
+    }
+}
+
+impl From<EllipseAxes> for Aperture {
+    fn from(axes: EllipseAxes) -> Self {
+        Aperture::Elliptical { a: axes.a, b: axes.b, theta: axes.theta }
+    }
+}
+
+/// Byte to write for each requested transmission level, correcting for the
+/// LCD's response. The identity until the panel is calibrated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lut(pub Vec<u8>);
+
+impl Default for Lut {
+    fn default() -> Self {
+        Lut((0 ..= 255).collect())
+    }
+}
+
+impl Lut {
+    pub fn apply(&self, level: u8) -> u8 {
+        self.0[level as usize]
+    }
+}
+
+/// Turns a pattern on a coarse grid into framebuffer bytes. The grid is
+/// stretched over the region, rotated about its center, shifted, limited to
+/// the aperture stop and mapped through the LCD response; everything else is
+/// dark. Every capture mode draws through this.
+///
+/// Positions are in physical pixels, and each pixel or subpixel (depending
+/// on `addressing`) shows the pattern at its own center, so rotations and
+/// apertures keep their shape whatever the subpixel layout.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub geometry: DisplayGeometry,
+    pub addressing: Addressing,
+    /// Upper left corner and size of the region the pattern fills.
+    pub origin: (f64, f64),
+    pub size: (f64, f64),
+    /// Aperture stop, in pixels about the optical axis.
+    pub aperture: Aperture,
+    /// Clockwise rotation of the pattern about the optical axis, in radians.
+    pub rotation: RotationAngle,
+    /// Offset of the optical axis from the center of the region.
+    pub translation: (f64, f64),
+    /// LCD response of each byte of a pixel, in memory order.
+    pub response: [Lut; 3],
+}
+
+impl Default for Pipeline {
+    /// The capture region, at subpixel resolution across the stripes.
+    fn default() -> Self {
+        let (x, y) = crate::capture::SCAN_ORIGIN;
+        let size = crate::capture::SCAN_SIZE as f64;
+        Pipeline {
+            geometry: DisplayGeometry::default(),
+            addressing: Addressing::Subpixel,
+            origin: (x as f64, y as f64),
+            size: (size / 3.0, size),
+            aperture: Aperture::Open,
+            rotation: 0.0,
+            translation: (0.0, 0.0),
+            response: Default::default(),
+        }
+    }
+}
+
+impl Pipeline {
+    fn center(&self) -> (f64, f64) {
+        (self.origin.0 + self.size.0 / 2.0 + self.translation.0,
+         self.origin.1 + self.size.1 / 2.0 + self.translation.1)
+    }
+
+    // Pixels the rotated region can cover.
+    fn bounds(&self) -> (Range<u32>, Range<u32>) {
+        let geometry = &self.geometry;
+        let (w, h) = self.size;
+        let center = self.center();
+        let (sin, cos) = self.rotation.sin_cos();
+        // Half extents of the rotated region's bounding box.
+        let reach = (0.5 * (w * cos.abs() + h * sin.abs()),
+                     0.5 * (w * sin.abs() + h * cos.abs()));
+        let clamp = |v: f64, max: u32| v.max(0.0).min(max as f64) as u32;
+        (clamp((center.0 - reach.0).floor(), geometry.width)
+             .. clamp((center.0 + reach.0).ceil(), geometry.width),
+         clamp((center.1 - reach.1).floor(), geometry.height)
+             .. clamp((center.1 + reach.1).ceil(), geometry.height))
+    }
+
+    /// Writes `pattern` into the framebuffer `buf`.
+    pub fn render(&self, pattern: &Mask, buf: &mut [u8]) {
+        buf.fill(0);
+        self.draw(pattern, buf);
+    }
+
+    /// Like [`Pipeline::render`], into a buffer that is already dark outside
+    /// the region this pipeline draws in, e.g. one it drew before. Only that
+    /// region is cleared.
+    pub fn redraw(&self, pattern: &Mask, buf: &mut [u8]) {
+        for row in self.rows() {
+            buf[row].fill(0);
+        }
+        self.draw(pattern, buf);
+    }
+
+    // Framebuffer bytes of each row of the region this pipeline draws in.
+    fn rows(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let (xs, ys) = self.bounds();
+        let ys = if xs.is_empty() { 0 .. 0 } else { ys };
+        ys.map(move |y| self.geometry.offset(xs.start, y, 0) .. self.geometry.offset(xs.end, y, 0))
+    }
+
+    fn draw(&self, pattern: &Mask, buf: &mut [u8]) {
+        let geometry = &self.geometry;
+        let (w, h) = self.size;
+        let center = self.center();
+        let cell = (w / pattern.width() as f64, h / pattern.height() as f64);
+        let (sin, cos) = self.rotation.sin_cos();
+        let sample = |x: f64, y: f64| -> Option<u8> {
+            let (dx, dy) = (x - center.0, y - center.1);
+            if !self.aperture.contains(dx, dy) {
+                return None;
+            }
+            // Undo the rotation to find which cell lands here.
+            let u = cos * dx + sin * dy + w / 2.0;
+            let v = -sin * dx + cos * dy + h / 2.0;
+            if u < 0.0 || v < 0.0 || u >= w || v >= h {
+                return None;
+            }
+            let px = u32::min((u / cell.0) as u32, pattern.width() - 1);
+            let py = u32::min((v / cell.1) as u32, pattern.height() - 1);
+            Some(pattern.get_pixel(px, py).0[0])
+        };
+
+        let (xs, ys) = self.bounds();
+        let subpixels = [geometry.subpixel_center(0),
+                         geometry.subpixel_center(1),
+                         geometry.subpixel_center(2)];
+        for y in ys {
+            for x in xs.clone() {
+                let (fx, fy) = (x as f64, y as f64);
+                match self.addressing {
+                    Addressing::Pixel => {
+                        if let Some(value) = sample(fx + 0.5, fy + 0.5) {
+                            for (byte, response) in self.response.iter().enumerate() {
+                                buf[geometry.offset(x, y, byte)] = response.apply(value);
+                            }
+                        }
+                    },
+                    Addressing::Subpixel => {
+                        for (byte, (ox, oy)) in subpixels.iter().enumerate() {
+                            if let Some(value) = sample(fx + ox, fy + oy) {
+                                buf[geometry.offset(x, y, byte)] = self.response[byte].apply(value);
+                            }
+                        }
+                    },
+                }
+            }
+        }
     }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Content<K> {
+    Blank,
+    Pattern(K, RotationAngle),
+}
+
+/// What each of the two framebuffers `lcd::run` alternates between holds, so
+/// that a pattern shown for several refreshes is only drawn into each once,
+/// and a new one only clears the region the pipeline draws in. The region last
+/// drawn is kept, so a pattern drawn into one buffer is copied rather than
+/// drawn again into the other. Everything but the pipeline's rotation must
+/// stay the same while this is in use.
+#[derive(Debug, Clone)]
+pub struct Framebuffers<K> {
+    held: [Option<Content<K>>; 2],
+    drawn: Option<(Content<K>, Vec<u8>)>,
+}
+
+impl<K> Default for Framebuffers<K> {
+    fn default() -> Self {
+        Framebuffers { held: [None, None], drawn: None }
+    }
+}
+
+impl<K: Clone + PartialEq> Framebuffers<K> {
+    /// Draws the pattern identified by `key` into buffer `index` through
+    /// `pipeline`, unless it's already there; `pattern` is only called if
+    /// it has to be drawn.
+    pub fn render<P: Borrow<Mask>>(
+        &mut self,
+        index: usize,
+        buf: &mut [u8],
+        pipeline: &Pipeline,
+        key: K,
+        pattern: impl FnOnce() -> P,
+    ) {
+        let content = Content::Pattern(key, pipeline.rotation);
+        let dark_outside = match &self.held[index] {
+            Some(held) if *held == content => return,
+            Some(Content::Blank) => true,
+            Some(Content::Pattern(_, rotation)) => *rotation == pipeline.rotation,
+            None => false,
+        };
+        match &self.drawn {
+            Some((drawn, bytes)) if *drawn == content => {
+                if !dark_outside {
+                    buf.fill(0);
+                }
+                let mut rest = &bytes[..];
+                for row in pipeline.rows() {
+                    let (line, next) = rest.split_at(row.len());
+                    buf[row].copy_from_slice(line);
+                    rest = next;
+                }
+            },
+            _ => {
+                if dark_outside {
+                    pipeline.redraw(pattern().borrow(), buf);
+                } else {
+                    pipeline.render(pattern().borrow(), buf);
+                }
+                let bytes = pipeline.rows().flat_map(|row| buf[row].iter().copied()).collect();
+                self.drawn = Some((content.clone(), bytes));
+            },
+        }
+        self.held[index] = Some(content);
+    }
+
+    /// Blacks out buffer `index`.
+    pub fn blank(&mut self, index: usize, buf: &mut [u8]) {
+        if self.held[index] != Some(Content::Blank) {
+            buf.fill(0);
+            self.held[index] = Some(Content::Blank);
+        }
+    }
+}
 
+/// Blacks out everything in `mask` outside `aperture` about its center.
+pub fn apply_aperture(mask: &mut Mask, aperture: &Aperture) {
     let (w, h) = mask.dimensions();
     let center = (w as f64 / 2.0, h as f64 / 2.0);
-    let cutoff_squared = cutoff * cutoff;
     for (x, y, pixel) in mask.enumerate_pixels_mut() {
-        let position = (x as f64, y as f64);
-        if distance_squared(position, center) > cutoff_squared {
+        if !aperture.contains(x as f64 - center.0, y as f64 - center.1) {
+            *pixel = Luma([0]);
+        }
+    }
+}
+
+pub fn apply_circular_cutoff(mask: &mut Mask, cutoff: PixelDistance) {
+    apply_aperture(mask, &Aperture::Circular(cutoff));
+}
+
+/// Blacks out everything in `mask` outside the ellipse `axes` centred on
+/// `center`, e.g. a beam footprint measured with `sep::aperture::ellipse_axes`.
+pub fn apply_elliptical_cutoff(mask: &mut Mask, center: (f64, f64), axes: &EllipseAxes) {
+    let aperture = Aperture::from(*axes);
+    for (x, y, pixel) in mask.enumerate_pixels_mut() {
+        if !aperture.contains(x as f64 - center.0, y as f64 - center.1) {
             *pixel = Luma([0]);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An 8 × 4 pixel panel with the pattern filling its middle 4 × 4 pixels.
+    fn pipeline() -> Pipeline {
+        Pipeline {
+            geometry: DisplayGeometry { width: 8, height: 4, ..DisplayGeometry::default() },
+            addressing: Addressing::Pixel,
+            origin: (2.0, 0.0),
+            size: (4.0, 4.0),
+            ..Pipeline::default()
+        }
+    }
+
+    fn quadrants() -> Mask {
+        Mask::from_raw(2, 2, vec![10, 20, 30, 40]).unwrap()
+    }
+
+    // One value per pixel, checking all its subpixels agree.
+    fn render(pipeline: &Pipeline) -> Vec<u8> {
+        let mut buf = vec![99; 3 * 32];
+        pipeline.render(&quadrants(), &mut buf);
+        buf.chunks(3).map(|pixel| {
+            assert!(pixel.iter().all(|v| *v == pixel[0]));
+            pixel[0]
+        }).collect()
+    }
+
+    #[test]
+    fn elliptical_cutoff_keeps_rotated_ellipse() {
+        let mut mask = Mask::from_pixel(9, 9, Luma([255]));
+        let axes = EllipseAxes { a: 3.0, b: 0.5, theta: std::f64::consts::FRAC_PI_4 };
+        apply_elliptical_cutoff(&mut mask, (5.0, 4.0), &axes);
+        let lit: Vec<(u32, u32)> = mask.enumerate_pixels()
+            .filter(|(_, _, p)| p.0[0] != 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert_eq!(lit, vec![(3, 2), (4, 3), (5, 4), (6, 5), (7, 6)]);
+    }
+
+    #[test]
+    fn pipeline_packs_subpixels_in_memory_order() {
+        let pipeline = Pipeline {
+            geometry: DisplayGeometry { width: 2, height: 1, ..DisplayGeometry::default() },
+            addressing: Addressing::Subpixel,
+            origin: (0.0, 0.0),
+            size: (2.0, 1.0),
+            ..Pipeline::default()
+        };
+        let mut buf = vec![0; 6];
+        pipeline.render(&Mask::from_raw(6, 1, vec![1, 2, 3, 4, 5, 6]).unwrap(), &mut buf);
+        // Rgb888 stores blue, the rightmost subpixel, first.
+        assert_eq!(buf, vec![3, 2, 1, 6, 5, 4]);
+    }
+
+    #[test]
+    fn pipeline_scales_pattern_into_region() {
+        assert_eq!(render(&pipeline()), vec![
+            0, 0, 10, 10, 20, 20, 0, 0,
+            0, 0, 10, 10, 20, 20, 0, 0,
+            0, 0, 30, 30, 40, 40, 0, 0,
+            0, 0, 30, 30, 40, 40, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn pipeline_rotates_and_shifts() {
+        let rotated = Pipeline {
+            rotation: std::f64::consts::FRAC_PI_2,
+            translation: (1.0, 0.0),
+            ..pipeline()
+        };
+        assert_eq!(render(&rotated), vec![
+            0, 0, 0, 30, 30, 10, 10, 0,
+            0, 0, 0, 30, 30, 10, 10, 0,
+            0, 0, 0, 40, 40, 20, 20, 0,
+            0, 0, 0, 40, 40, 20, 20, 0,
+        ]);
+    }
+
+    #[test]
+    fn pipeline_applies_aperture_and_response() {
+        let half = Lut((0 ..= 255).map(|v: u8| v / 2).collect());
+        let stopped = Pipeline {
+            aperture: Aperture::Polygon(vec![(-2.0, -2.0), (0.0, -2.0), (0.0, 2.0), (-2.0, 2.0)]),
+            response: [half.clone(), half.clone(), half],
+            ..pipeline()
+        };
+        assert_eq!(render(&stopped), vec![
+            0, 0, 5, 5, 0, 0, 0, 0,
+            0, 0, 5, 5, 0, 0, 0, 0,
+            0, 0, 15, 15, 0, 0, 0, 0,
+            0, 0, 15, 15, 0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn framebuffers_only_draw_what_changed() {
+        let mut buffers = Framebuffers::default();
+        let mut buf = vec![99; 3 * 32];
+        let drawn = std::cell::Cell::new(0);
+        let pattern = || {
+            drawn.set(drawn.get() + 1);
+            quadrants()
+        };
+        let mut pipeline = pipeline();
+        buffers.render(0, &mut buf, &pipeline, 1, pattern);
+        let mut expected = vec![0; 3 * 32];
+        pipeline.render(&quadrants(), &mut expected);
+        assert_eq!(buf, expected);
+        buffers.render(0, &mut buf, &pipeline, 1, pattern);
+        assert_eq!(drawn.get(), 1);
+
+        // The other buffer gets a copy of what was just drawn.
+        let mut other = vec![99; 3 * 32];
+        buffers.render(1, &mut other, &pipeline, 1, pattern);
+        assert_eq!((drawn.get(), &other), (1, &expected));
+
+        // Another pattern only clears the region, which covers what the
+        // first drew.
+        buf[0] = 7;
+        buffers.render(0, &mut buf, &pipeline, 2, || Mask::from_pixel(1, 1, Luma([5])));
+        assert_eq!(buf[0], 7);
+        assert!(buf[3 * 2 .. 3 * 6].iter().all(|v| *v == 5));
+
+        pipeline.rotation = std::f64::consts::FRAC_PI_2;
+        buffers.render(0, &mut buf, &pipeline, 2, pattern);
+        assert_eq!((drawn.get(), buf[0]), (2, 0));
+        buffers.blank(0, &mut buf);
+        assert!(buf.iter().all(|v| *v == 0));
+    }
+}
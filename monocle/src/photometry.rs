@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::path::Path;
+
+/// Count sensitivity of the photon counting head against wavelength, in nm
+/// and counts per second per picowatt.
+pub const SENSITIVITY: &str = include_str!("../res/count-sensitivity-vs-wavelength.csv");
+
+const PLANCK: f64 = 6.626_070_15e-34;
+const LIGHT_SPEED: f64 = 2.997_924_58e8;
+const BOLTZMANN: f64 = 1.380_649e-23;
+
+/// Flux density of an AB magnitude 0 source, in W m⁻² Hz⁻¹.
+const AB_ZERO: f64 = 3631e-26;
+
+/// Wavelength spectra are normalised to, in nm.
+pub const REFERENCE_WAVELENGTH: f64 = 550.0;
+
+/// Step of the integrals over wavelength, in nm.
+const STEP: f64 = 1.0;
+
+/// A function of wavelength tabulated at increasing wavelengths in nm, and
+/// interpolated linearly between them. Zero outside the table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Curve {
+    /// Parses `wavelength, value` lines; blank lines and lines starting with
+    /// `#` are skipped.
+    pub fn parse(text: &str) -> Result<Curve, Box<dyn Error>> {
+        let mut points = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (wavelength, value) = line.split_once(',')
+                .ok_or_else(|| format!("Line {} isn't `wavelength, value`", number + 1))?;
+            points.push((wavelength.trim().parse()?, value.trim().parse()?));
+        }
+        if points.len() < 2 {
+            return Err("A curve needs at least two points".into());
+        }
+        points.sort_by(|a: &(f64, f64), b| a.0.partial_cmp(&b.0).unwrap());
+        Ok(Curve { points })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Curve, Box<dyn Error>> {
+        Curve::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The detector's count sensitivity shipped in `res/`.
+    pub fn sensitivity() -> Curve {
+        Curve::parse(SENSITIVITY).unwrap()
+    }
+
+    /// Wavelengths the table covers.
+    pub fn range(&self) -> (f64, f64) {
+        (self.points[0].0, self.points[self.points.len() - 1].0)
+    }
+
+    pub fn value(&self, wavelength: f64) -> f64 {
+        let after = self.points.iter().position(|(w, _)| *w >= wavelength);
+        match after {
+            None => 0.0,
+            Some(0) if wavelength < self.points[0].0 => 0.0,
+            Some(0) => self.points[0].1,
+            Some(i) => {
+                let ((w0, v0), (w1, v1)) = (self.points[i - 1], self.points[i]);
+                v0 + (v1 - v0) * (wavelength - w0) / (w1 - w0)
+            },
+        }
+    }
+}
+
+/// Spectral shape of a source, as flux density per unit wavelength.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Spectrum {
+    /// A black body at this temperature in kelvin.
+    Blackbody(f64),
+    /// A tabulated spectral energy distribution, in any units per nm.
+    Tabulated(Curve),
+    /// Flat in flux density per unit frequency, like the AB magnitude system.
+    FlatFrequency,
+}
+
+impl Spectrum {
+    fn unnormalised(&self, wavelength: f64) -> f64 {
+        let metres = wavelength * 1e-9;
+        match self {
+            Spectrum::Blackbody(temperature) => {
+                let exponent = PLANCK * LIGHT_SPEED / (metres * BOLTZMANN * temperature);
+                1.0 / (metres.powi(5) * exponent.exp_m1())
+            },
+            Spectrum::Tabulated(curve) => curve.value(wavelength),
+            Spectrum::FlatFrequency => 1.0 / (metres * metres),
+        }
+    }
+
+    /// Flux density at `wavelength` in nm, relative to that at
+    /// [`REFERENCE_WAVELENGTH`].
+    pub fn relative(&self, wavelength: f64) -> f64 {
+        self.unnormalised(wavelength) / self.unnormalised(REFERENCE_WAVELENGTH)
+    }
+}
+
+/// The optical path from the sky to the counter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    /// Collecting area of the telescope, in m².
+    pub area: f64,
+    /// Detector count sensitivity, in counts s⁻¹ pW⁻¹.
+    pub sensitivity: Curve,
+    /// Transmissions of the filter, the open LCD and anything else in the
+    /// beam, between 0 and 1.
+    pub transmissions: Vec<Curve>,
+}
+
+impl Instrument {
+    /// The shipped detector curve behind a telescope of `area` m², with
+    /// nothing else in the beam.
+    pub fn new(area: f64) -> Instrument {
+        Instrument { area, sensitivity: Curve::sensitivity(), transmissions: Vec::new() }
+    }
+
+    /// Counts per second per W m⁻² nm⁻¹ of flux density at `wavelength`.
+    pub fn response(&self, wavelength: f64) -> f64 {
+        let transmission: f64 = self.transmissions.iter().map(|c| c.value(wavelength)).product();
+        self.area * 1e12 * self.sensitivity.value(wavelength) * transmission
+    }
+
+    /// Count rate from a source of this spectrum with a flux density of
+    /// 1 W m⁻² nm⁻¹ at [`REFERENCE_WAVELENGTH`].
+    pub fn rate_per_flux(&self, spectrum: &Spectrum) -> f64 {
+        let (start, end) = self.sensitivity.range();
+        let steps = ((end - start) / STEP).ceil() as usize;
+        (0 ..= steps)
+            .map(|i| start + i as f64 * STEP)
+            .map(|wavelength| self.response(wavelength) * spectrum.relative(wavelength) * STEP)
+            .sum()
+    }
+
+    /// Count rate from a source of this spectrum with flux density `flux`
+    /// W m⁻² nm⁻¹ at [`REFERENCE_WAVELENGTH`].
+    pub fn rate(&self, spectrum: &Spectrum, flux: f64) -> f64 {
+        flux * self.rate_per_flux(spectrum)
+    }
+
+    /// Flux density at [`REFERENCE_WAVELENGTH`] in W m⁻² nm⁻¹ of a source of
+    /// this spectrum giving `rate` counts per second.
+    pub fn flux(&self, spectrum: &Spectrum, rate: f64) -> f64 {
+        rate / self.rate_per_flux(spectrum)
+    }
+
+    /// Count rate of an AB magnitude 0 source.
+    pub fn zero_point(&self) -> f64 {
+        let metres = REFERENCE_WAVELENGTH * 1e-9;
+        let flux = AB_ZERO * LIGHT_SPEED / (metres * metres) * 1e-9;
+        self.rate(&Spectrum::FlatFrequency, flux)
+    }
+
+    /// AB magnitude in this instrument's band of a source giving `rate`
+    /// counts per second.
+    pub fn magnitude(&self, rate: f64) -> f64 {
+        -2.5 * (rate / self.zero_point()).log10()
+    }
+
+    /// Count rate of a source of AB magnitude `magnitude` in this band.
+    pub fn magnitude_rate(&self, magnitude: f64) -> f64 {
+        self.zero_point() * 10f64.powf(-0.4 * magnitude)
+    }
+}
+
+/// Seconds of integration needed for a signal-to-noise ratio of `snr` on a
+/// source giving `rate` counts per second over `background` counts per
+/// second (dark counts and sky), both with Poisson noise, when the
+/// background is known exactly.
+pub fn exposure_time(rate: f64, background: f64, snr: f64) -> f64 {
+    snr * snr * (rate + background) / (rate * rate)
+}
+
+/// Signal-to-noise ratio after integrating for `seconds`.
+pub fn snr(rate: f64, background: f64, seconds: f64) -> f64 {
+    rate * seconds / ((rate + background) * seconds).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_shipped_sensitivity_curve() {
+        let curve = Curve::sensitivity();
+        assert_eq!(curve.points.len(), 58);
+        let (start, end) = curve.range();
+        assert!(start > 260.0 && start < 270.0 && end > 650.0 && end < 651.0);
+        let peak = curve.points.iter().cloned().fold((0.0, 0.0), |a, b| if b.1 > a.1 { b } else { a });
+        assert!(peak.0 > 400.0 && peak.0 < 450.0 && peak.1 > 4e5, "{:?}", peak);
+        assert_eq!(curve.value(100.0), 0.0);
+        assert_eq!(curve.value(1000.0), 0.0);
+    }
+
+    #[test]
+    fn conversions_are_consistent() {
+        let instrument = Instrument::new(0.01);
+        let sun = Spectrum::Blackbody(5772.0);
+        let rate = instrument.rate(&sun, 1e-15);
+        assert!((instrument.flux(&sun, rate) / 1e-15 - 1.0).abs() < 1e-12);
+
+        // Hotter stars are bluer, where the detector is more sensitive.
+        let hot = instrument.rate_per_flux(&Spectrum::Blackbody(10000.0));
+        let cool = instrument.rate_per_flux(&Spectrum::Blackbody(3000.0));
+        assert!(hot > cool);
+
+        assert!(instrument.magnitude(instrument.zero_point()).abs() < 1e-12);
+        let faint = instrument.magnitude_rate(5.0);
+        assert!((instrument.magnitude(faint) - 5.0).abs() < 1e-9);
+        assert!((faint / instrument.zero_point() - 0.01).abs() < 1e-12);
+
+        let (rate, background) = (400.0, 100.0);
+        let seconds = exposure_time(rate, background, 20.0);
+        assert!((snr(rate, background, seconds) - 20.0).abs() < 1e-9);
+    }
+}
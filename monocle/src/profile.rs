@@ -0,0 +1,319 @@
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::*;
+use crate::display::DisplayGeometry;
+use crate::gamma::ResponseCurve;
+use crate::gpio::PinMap;
+use crate::latency::Latency;
+use crate::gpio::Reading;
+use crate::mask::Lut;
+use crate::reconstruct::Image;
+
+/// Where the Pi keeps every version of its calibration.
+pub const PI_PROFILES: &str = "/calibration";
+
+/// Where the laptop keeps the versions it has fetched, next to captures.
+pub const LAPTOP_PROFILES: &str = "/home/remy/compressive-output/profiles";
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// A calibration result and when it was measured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibrated<T> {
+    pub value: T,
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+}
+
+impl<T> Calibrated<T> {
+    pub fn now(value: T) -> Calibrated<T> {
+        Calibrated { value, at: now() }
+    }
+}
+
+/// The telescope's image on the panel, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ApertureCalibration {
+    /// Offset from the screen centre, as for `CalibrateReq::Cutoff`.
+    pub center: (f64, f64),
+    pub radius: f64,
+    /// Coefficients `cxx`, `cyy`, `cxy` of the ellipse around `center`, if
+    /// one was fitted.
+    #[serde(default)]
+    pub ellipse: Option<(f64, f64, f64)>,
+}
+
+/// Count rate in counts per second with the panel black: detector dark
+/// counts plus light leaking through the opaque LCD.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DarkRate {
+    pub mean: f64,
+    /// Variance of the rate measured by a single window.
+    pub variance: f64,
+    /// Number of windows `mean` was taken over.
+    pub windows: usize,
+}
+
+impl DarkRate {
+    pub fn measure<'a>(readings: impl Iterator<Item = &'a Reading>) -> Option<DarkRate> {
+        let counts: Vec<f64> = readings.map(|reading| reading.rate()).collect();
+        if counts.len() < 2 {
+            return None;
+        }
+        let n = counts.len() as f64;
+        let mean = counts.iter().sum::<f64>() / n;
+        let variance = counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        Some(DarkRate { mean, variance, windows: counts.len() })
+    }
+
+    /// Standard error of `mean`.
+    pub fn error(&self) -> f64 {
+        (self.variance / self.windows as f64).sqrt()
+    }
+}
+
+/// Cells of a flat field seeing less than this fraction of the brightest
+/// one are taken to be outside the aperture.
+const FLAT_FLOOR: f64 = 0.05;
+
+/// Relative sensitivity of each cell of a `resolution`² grid over the mask
+/// region, in row-major order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlatField {
+    pub resolution: usize,
+    pub gains: Vec<f64>,
+}
+
+impl FlatField {
+    /// Gains from the reconstruction of a uniform source, relative to the
+    /// mean of the cells inside the aperture; cells outside get zero.
+    pub fn measure(image: &Image) -> Option<FlatField> {
+        let (height, width) = image.dim();
+        let peak = image.fold(0.0, |a: f64, b| a.max(*b));
+        if height != width || peak <= 0.0 {
+            return None;
+        }
+        let lit: Vec<f64> = image.iter().cloned().filter(|v| *v >= FLAT_FLOOR * peak).collect();
+        let mean = lit.iter().sum::<f64>() / lit.len() as f64;
+        Some(FlatField {
+            resolution: width,
+            gains: image.iter()
+                .map(|v| if *v >= FLAT_FLOOR * peak { v / mean } else { 0.0 })
+                .collect(),
+        })
+    }
+
+    /// Gain of the cell covering `(y, x)` of an image of size `dim`.
+    pub fn gain(&self, (y, x): (usize, usize), dim: (usize, usize)) -> f64 {
+        let cell = |i: usize, n: usize| (i * self.resolution / n).min(self.resolution - 1);
+        self.gains[cell(y, dim.0) * self.resolution + cell(x, dim.1)]
+    }
+}
+
+/// Calibration results that captures depend on. Every change is saved as a
+/// new version, so captures can name the one they used.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Zero for the empty profile, then counting up from one.
+    pub version: u32,
+    /// Version this one was updated or rolled back from.
+    pub parent: Option<u32>,
+    /// When this version was saved, in seconds since the Unix epoch.
+    pub saved: u64,
+    #[serde(default)]
+    pub latency: Option<Calibrated<Latency>>,
+    /// LCD response of each channel.
+    #[serde(default)]
+    pub response: Option<Calibrated<Vec<ResponseCurve>>>,
+    #[serde(default)]
+    pub aperture: Option<Calibrated<ApertureCalibration>>,
+    #[serde(default)]
+    pub dark: Option<Calibrated<DarkRate>>,
+    #[serde(default)]
+    pub flat_field: Option<Calibrated<FlatField>>,
+    #[serde(default)]
+    pub pins: Option<Calibrated<PinMap>>,
+}
+
+impl Profile {
+    /// Takes every calibration `other` has, keeping the rest. [`Store::update`]
+    /// rejects the result if `other` brought invalid pins.
+    pub fn merge(&mut self, other: Profile) {
+        fn take<T>(mine: &mut Option<T>, theirs: Option<T>) {
+            if theirs.is_some() {
+                *mine = theirs;
+            }
+        }
+        take(&mut self.latency, other.latency);
+        take(&mut self.response, other.response);
+        take(&mut self.aperture, other.aperture);
+        take(&mut self.dark, other.dark);
+        take(&mut self.flat_field, other.flat_field);
+        take(&mut self.pins, other.pins);
+    }
+
+    pub fn latency(&self) -> Option<Latency> {
+        self.latency.as_ref().map(|c| c.value)
+    }
+
+    /// Inverse response of each byte of a pixel of `geometry`; the identity
+    /// for channels that weren't calibrated.
+    pub fn response(&self, geometry: &DisplayGeometry) -> [Lut; 3] {
+        let curves = self.response.as_ref().map_or(&[][..], |c| &c.value[..]);
+        let lut = |byte: usize| {
+            curves.iter()
+                .find(|curve| curve.channel == geometry.bytes[byte])
+                .map(|curve| curve.inverse())
+                .unwrap_or_default()
+        };
+        [lut(0), lut(1), lut(2)]
+    }
+
+    /// Fails if a calibration couldn't be used, e.g. pins outside the bank.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(pins) = &self.pins {
+            pins.value.check()?;
+        }
+        Ok(())
+    }
+
+    pub fn pins(&self) -> PinMap {
+        self.pins.as_ref().map(|c| c.value.clone()).unwrap_or_default()
+    }
+}
+
+/// A directory holding one CBOR file per profile version.
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Store {
+    pub fn new(root: impl Into<PathBuf>) -> Store {
+        Store { root: root.into() }
+    }
+
+    /// The store captures on the Pi use.
+    pub fn pi() -> Store {
+        Store::new(PI_PROFILES)
+    }
+
+    fn path(&self, version: u32) -> PathBuf {
+        self.root.join(format!("v{}.cbor", version))
+    }
+
+    /// Stored versions in increasing order.
+    pub fn versions(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut versions = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            let version = name.to_str().and_then(|name| {
+                name.strip_prefix('v')?.strip_suffix(".cbor")?.parse::<u32>().ok()
+            });
+            versions.extend(version);
+        }
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    pub fn get(&self, version: u32) -> Result<Profile, Box<dyn Error>> {
+        if version == 0 {
+            return Ok(Profile::default());
+        }
+        Ok(serde_cbor::from_slice(&std::fs::read(self.path(version))?)?)
+    }
+
+    /// The latest version, or the empty profile.
+    pub fn latest(&self) -> Result<Profile, Box<dyn Error>> {
+        match self.versions()?.last() {
+            Some(version) => self.get(*version),
+            None => Ok(Profile::default()),
+        }
+    }
+
+    /// Writes `profile` under its own version, e.g. one fetched from the Pi.
+    pub fn put(&self, profile: &Profile) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(&self.root)?;
+        std::fs::write(self.path(profile.version), serde_cbor::to_vec(profile)?)?;
+        Ok(())
+    }
+
+    // Saves `profile` as the next version, derived from `parent`.
+    fn append(&self, mut profile: Profile, parent: u32) -> Result<Profile, Box<dyn Error>> {
+        profile.version = self.versions()?.last().map_or(1, |v| v + 1);
+        profile.parent = Some(parent);
+        profile.saved = now();
+        self.put(&profile)?;
+        info!("Saved calibration profile version {}", profile.version);
+        Ok(profile)
+    }
+
+    /// Saves a new version with `change` applied to the latest one, unless
+    /// that leaves it invalid.
+    pub fn update(&self, change: impl FnOnce(&mut Profile))
+                  -> Result<Profile, Box<dyn Error>>
+    {
+        let mut profile = self.latest()?;
+        let parent = profile.version;
+        change(&mut profile);
+        profile.check()?;
+        self.append(profile, parent)
+    }
+
+    /// Saves a new version with the calibrations of `version`, so that
+    /// captures keep referring to the versions they used.
+    pub fn rollback(&self, version: u32) -> Result<Profile, Box<dyn Error>> {
+        let profile = self.get(version)?;
+        self.append(profile, version)
+    }
+}
+
+/// The profile captures on the Pi use now, or the empty one if the store
+/// can't be read.
+pub fn current() -> Profile {
+    Store::pi().latest().unwrap_or_else(|e| {
+        warn!("Ignoring unreadable calibration profiles in {}: {}", PI_PROFILES, e);
+        Profile::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_and_rollbacks_append_versions() {
+        let root = std::env::temp_dir()
+            .join(format!("monocle-profiles-{}", std::process::id()));
+        let store = Store::new(&root);
+        assert_eq!(store.latest().unwrap(), Profile::default());
+
+        let dark = |mean| Some(Calibrated::now(DarkRate { mean, variance: mean, windows: 100 }));
+        let first = store.update(|p| p.dark = dark(3.0)).unwrap();
+        let second = store.update(|p| p.dark = dark(5.0)).unwrap();
+        assert_eq!((first.version, first.parent), (1, Some(0)));
+        assert_eq!((second.version, second.parent), (2, Some(1)));
+
+        let rolled = store.rollback(1).unwrap();
+        assert_eq!((rolled.version, rolled.parent), (3, Some(1)));
+        assert_eq!(rolled.dark.as_ref().map(|c| c.value.mean), Some(3.0));
+        assert_eq!(store.versions().unwrap(), vec![1, 2, 3]);
+        assert_eq!(store.get(2).unwrap(), second);
+        assert_eq!(store.latest().unwrap(), rolled);
+
+        let mut pins = PinMap::default();
+        pins.counter[11] = PinMap::LINES;
+        let bad = Profile { pins: Some(Calibrated::now(pins)), ..Profile::default() };
+        assert!(store.update(|p| p.merge(bad)).is_err());
+        assert_eq!(store.versions().unwrap(), vec![1, 2, 3]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use ndarray::{Array1, Array2};
+use serde_derive::Deserialize;
+use crate::adaptive::{HaarSearch, Options, QuadtreeSearch, replay};
+use crate::api::{Capture, MaskSeq};
+use crate::gpio::Reading;
+use crate::profile::{FlatField, LAPTOP_PROFILES, Profile, Store};
+use crate::scanline::{Frame, ScanLine};
+use crate::transform::{Complex, fft_2d, fwht_2d};
+
+/// A linear intensity image, indexed by `(y, x)`.
+pub type Image = Array2<f64>;
+
+// What the client wrote before captures carried their mask sequence:
+// `(resolution, TakePictureResp { pulses })`, always from a scanning box.
+#[derive(Deserialize)]
+struct LegacyTakePictureResp {
+    pulses: Vec<Vec<((Frame, ScanLine), Reading)>>,
+}
+
+pub fn save(capture: &Capture, path: impl AsRef<Path>)
+            -> Result<(), Box<dyn Error>>
+{
+    std::fs::write(path, serde_cbor::to_vec(capture)?)?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Capture, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    if let Ok(capture) = serde_cbor::from_slice::<Capture>(&data) {
+        return Ok(capture);
+    }
+    let (_, legacy): (usize, LegacyTakePictureResp) =
+        serde_cbor::from_slice(&data)?;
+    let pulses = legacy.pulses.into_iter().next()
+        .ok_or("Legacy capture contains no pulse sets")?;
+    Ok(Capture {
+        masks: MaskSeq::ScanningBox,
+        pulses,
+        frames: Vec::new(),
+        differential: false,
+        monitor: Vec::new(),
+        angles: Vec::new(),
+        flips: Vec::new(),
+        timing: None,
+        latency: None,
+        profile: None,
+    })
+}
+
+/// Count rate in counts per second over all the windows, or `None` if there
+/// were none.
+///
+/// The server uses this to steer adaptive sequences, so offline replay must
+/// average in exactly the same way to reproduce the masks it chose.
+pub fn mean_rate<'a>(readings: impl Iterator<Item = &'a Reading>) -> Option<f64> {
+    let (seconds, counts) = readings.fold((0.0, 0u64), |(s, n), reading| {
+        (s + reading.seconds(), n + reading.counter)
+    });
+    if seconds == 0.0 {
+        None
+    } else {
+        Some(counts as f64 / seconds)
+    }
+}
+
+/// Flip whose content the window at `(frame, scanline)` saw: the one it
+/// followed, or an earlier one if the panel's latency is known.
+pub fn seen_frame(capture: &Capture, (frame, line): (Frame, ScanLine)) -> Option<Frame> {
+    match (&capture.timing, &capture.latency) {
+        (Some(timing), Some(latency)) => {
+            let v_total = timing.v_total() as f64;
+            let position = frame as f64 * v_total + line as f64 - latency.lines;
+            (position >= 0.0).then(|| (position / v_total).floor() as Frame)
+        },
+        _ => Some(frame),
+    }
+}
+
+/// Number of masks displayed, counting inverses of differential captures.
+pub fn displayed(capture: &Capture) -> usize {
+    capture.masks.len() * if capture.differential { 2 } else { 1 }
+}
+
+/// Windows tagged with the mask they measure, indexed as in [`displayed`].
+pub fn readings_by_mask(capture: &Capture) -> HashMap<usize, Vec<&Reading>> {
+    let mask_of = |frame: Frame| -> Option<usize> {
+        if capture.frames.is_empty() {
+            Some(frame as usize)
+        } else {
+            capture.frames.get(frame as usize).cloned().flatten()
+        }
+    };
+    let mut mask_map: HashMap<usize, Vec<&Reading>> = HashMap::new();
+    for (position, reading) in &capture.pulses {
+        if let Some(mask) = seen_frame(capture, *position).and_then(mask_of) {
+            mask_map.entry(mask).or_default().push(reading);
+        }
+    }
+    mask_map
+}
+
+/// Count rate while each mask was displayed, indexed by mask as
+/// in [`displayed`]. Masks that received no windows at all are `None`.
+pub fn measurements(capture: &Capture) -> Vec<Option<f64>> {
+    let mask_map = readings_by_mask(capture);
+    (0 .. displayed(capture))
+        .map(|i| mask_map.get(&i)
+             .and_then(|readings| mean_rate(readings.iter().cloned())))
+        .collect()
+}
+
+/// Standard error of each of [`measurements`], from the scatter of its
+/// windows' rates, or assuming Poisson counts if it only has one.
+pub fn measurement_errors(capture: &Capture) -> Vec<Option<f64>> {
+    let mask_map = readings_by_mask(capture);
+    (0 .. displayed(capture))
+        .map(|i| {
+            let readings = mask_map.get(&i)?;
+            let rates: Vec<f64> = readings.iter().map(|reading| reading.rate()).collect();
+            let n = rates.len() as f64;
+            let mean = rates.iter().sum::<f64>() / n;
+            match readings.as_slice() {
+                [] => None,
+                [reading] => Some((reading.counter as f64).sqrt() / reading.seconds()),
+                _ => {
+                    let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>()
+                        / (n - 1.0);
+                    Some((variance / n).sqrt())
+                },
+            }
+        })
+        .collect()
+}
+
+pub fn reconstruct(capture: &Capture) -> Result<Image, Box<dyn Error>> {
+    reconstruct_with(capture, &Regularizer::default())
+}
+
+/// Divides `image` by the flat field, resampled to its grid. Cells the flat
+/// field saw no light in are zeroed rather than amplified.
+pub fn flatten(image: &Image, flat: &FlatField) -> Image {
+    Image::from_shape_fn(image.dim(), |index| {
+        let gain = flat.gain(index, image.dim());
+        if gain > 0.0 { image[index] / gain } else { 0.0 }
+    })
+}
+
+/// The calibration profile `capture` was taken with, from the copies
+/// fetched to the laptop; the empty profile if it isn't there.
+pub fn capture_profile(capture: &Capture) -> Profile {
+    let version = match capture.profile {
+        Some(version) => version,
+        None => return Profile::default(),
+    };
+    Store::new(LAPTOP_PROFILES).get(version).unwrap_or_else(|e| {
+        println!("Calibration profile {} isn't available ({}); fetch it with `profile {}`",
+                 version, e, version);
+        Profile::default()
+    })
+}
+
+/// Splits interleaved pattern/inverse measurements into their differences
+/// and sums.
+pub fn pairs(values: &[Option<f64>]) -> (Vec<Option<f64>>, Vec<Option<f64>>) {
+    values.chunks(2).map(|pair| match *pair {
+        [Some(pattern), Some(inverse)] => {
+            (Some(pattern - inverse), Some(pattern + inverse))
+        },
+        _ => (None, None),
+    }).unzip()
+}
+
+/// For mask `p` and scene `x`, a pattern/inverse pair measures
+/// `⟨2p − 1, x⟩ = 2⟨p, x⟩ − T`, without the dark rate and with `T` the total
+/// flux. Adding back the mean of the pair sums in place of each pair's own
+/// total gives single-mask values in which sky and detector drift cancel.
+pub fn combine_pairs(values: &[Option<f64>]) -> Vec<Option<f64>> {
+    let (differences, sums) = pairs(values);
+    let sums: Vec<f64> = sums.into_iter().flatten().collect();
+    let total = sums.iter().sum::<f64>() / (sums.len().max(1) as f64);
+    differences.into_iter()
+        .map(|difference| difference.map(|d| (d + total) / 2.0))
+        .collect()
+}
+
+/// As [`reconstruct`], with `regularizer` used if the capture is compressive.
+pub fn reconstruct_with(capture: &Capture, regularizer: &Regularizer)
+                        -> Result<Image, Box<dyn Error>>
+{
+    let mut values = measurements(capture);
+    if capture.differential {
+        values = combine_pairs(&values);
+    }
+    reconstruct_from(&capture.masks, &values, regularizer)
+}
+
+/// Reconstructs `capture` with the calibrations of `profile`: the dark rate
+/// is subtracted from every measurement, or from the image of adaptive
+/// sequences, and the flat field divided out. Also returns the standard error
+/// of each pixel if the solver is linear.
+pub fn reconstruct_calibrated(
+    capture: &Capture,
+    regularizer: &Regularizer,
+    profile: &Profile,
+) -> Result<(Image, Option<Image>), Box<dyn Error>> {
+    let mut values = measurements(capture);
+    let mut errors = measurement_errors(capture);
+    if capture.differential {
+        values = combine_pairs(&values);
+        errors = errors.chunks(2).map(|pair| match *pair {
+            [Some(pattern), Some(inverse)] => Some(pattern.hypot(inverse) / 2.0),
+            _ => None,
+        }).collect();
+    }
+    let dark = profile.dark.as_ref().map(|c| c.value);
+    let dark_mean = dark.map_or(0.0, |dark| dark.mean);
+    let mut image = match capture.masks {
+        MaskSeq::AdaptiveQuadtree { .. } | MaskSeq::AdaptiveHaar { .. } => {
+            replay_adaptive(&capture.masks, &values, dark_mean)?
+        },
+        _ => {
+            // Pairs cancel the dark rate in their difference but keep it
+            // once in the total added back, so it is subtracted once either
+            // way.
+            for value in values.iter_mut().flatten() {
+                *value -= dark_mean;
+            }
+            reconstruct_from(&capture.masks, &values, regularizer)?
+        },
+    };
+    let mut uncertainty = propagate_errors(
+        &capture.masks, &errors, dark.map_or(0.0, |dark| dark.error()));
+    if let Some(flat) = &profile.flat_field {
+        image = flatten(&image, &flat.value);
+        uncertainty = uncertainty.map(|u| flatten(&u, &flat.value));
+    }
+    Ok((image, uncertainty))
+}
+
+/// Standard error of each pixel reconstructed from values with independent
+/// `errors` and an error `common` to all of them, such as that of the
+/// subtracted dark rate. `None` for compressive and adaptive sequences, whose
+/// solvers aren't linear.
+pub fn propagate_errors(masks: &MaskSeq, errors: &[Option<f64>], common: f64) -> Option<Image> {
+    match *masks {
+        MaskSeq::Random { .. } | MaskSeq::AdaptiveQuadtree { .. } | MaskSeq::AdaptiveHaar { .. } => {
+            return None;
+        },
+        // Each pixel is one measurement; skip building every basis image.
+        MaskSeq::ScanningBox => {
+            let n = masks.resolution();
+            return Some(Image::from_shape_fn((n, n), |(y, x)| {
+                errors[x + y * n].map_or(0.0, |error| error.hypot(common))
+            }));
+        },
+        _ => (),
+    }
+    let regularizer = Regularizer::default();
+    let response = |values: &[Option<f64>]| reconstruct_from(masks, values, &regularizer).ok();
+    let mut unit: Vec<Option<f64>> = errors.iter().map(|e| e.map(|_| 0.0)).collect();
+    let ones: Vec<Option<f64>> = errors.iter().map(|e| e.map(|_| 1.0)).collect();
+    let mut variance = response(&ones)?.mapv(|v| (v * common).powi(2));
+    for (k, error) in errors.iter().enumerate() {
+        if let Some(error) = error {
+            unit[k] = Some(1.0);
+            variance += &response(&unit)?.mapv(|v| (v * error).powi(2));
+            unit[k] = Some(0.0);
+        }
+    }
+    Some(variance.mapv(f64::sqrt))
+}
+
+/// Picks the solver matching the mask sequence that produced `values`. Fails
+/// if a measurement the solver can't do without is missing.
+pub fn reconstruct_from(
+    masks: &MaskSeq,
+    values: &[Option<f64>],
+    regularizer: &Regularizer,
+) -> Result<Image, Box<dyn Error>> {
+    Ok(match *masks {
+        MaskSeq::ScanningBox => direct(masks.resolution(), values),
+        MaskSeq::Hadamard(n) => hadamard(n, values).ok_or("The total flux wasn't measured")?,
+        MaskSeq::Fourier(n) => fourier(n, values),
+        MaskSeq::Random { .. } => compressive_with(masks, values, regularizer),
+        MaskSeq::AdaptiveQuadtree { .. } | MaskSeq::AdaptiveHaar { .. } => {
+            replay_adaptive(masks, values, 0.0)?
+        },
+        MaskSeq::Dark { .. } => dark(values),
+    })
+}
+
+// Replays an adaptive sequence with `dark` taken off the image.
+fn replay_adaptive(masks: &MaskSeq, values: &[Option<f64>], dark: f64)
+                   -> Result<Image, Box<dyn Error>>
+{
+    let options = Options::from_sequence(masks).ok_or("Not an adaptive sequence")?;
+    let image = match masks {
+        MaskSeq::AdaptiveQuadtree { .. } => replay::<QuadtreeSearch>(&options, values, dark),
+        MaskSeq::AdaptiveHaar { .. } => replay::<HaarSearch>(&options, values, dark),
+        _ => return Err("Not an adaptive sequence".into()),
+    };
+    image.ok_or_else(|| "A mask of the adaptive search wasn't measured, so it can't be replayed"
+        .into())
+}
+
+/// Each measurement is a single grid cell. Cells that weren't measured are
+/// left dark.
+pub fn direct(n: usize, values: &[Option<f64>]) -> Image {
+    let missing = values.iter().take(n * n).filter(|value| value.is_none()).count();
+    if missing > 0 {
+        println!("{} of {} cells weren't measured and are left dark", missing, n * n);
+    }
+    Image::from_shape_fn((n, n), |(y, x)| values[x + y * n].unwrap_or(0.0))
+}
+
+/// The mean over every frame, as a single pixel.
+pub fn dark(values: &[Option<f64>]) -> Image {
+    let measured: Vec<f64> = values.iter().flatten().cloned().collect();
+    Image::from_elem((1, 1), measured.iter().sum::<f64>() / measured.len().max(1) as f64)
+}
+
+/// Masks show `(1 + h)/2` for each ±1 Hadamard row `h`. The first row is all
+/// ones and so measures the total, which recovers `h·x = 2m − m₀`; `None` if
+/// it is missing, as every other coefficient depends on it.
+pub fn hadamard(n: usize, values: &[Option<f64>]) -> Option<Image> {
+    let total = values[0]?;
+    let mut coefficients = Array2::<f64>::from_shape_fn((n, n), |(u, v)| {
+        let index = v + u * n;
+        match values[index] {
+            Some(m) if index == 0 => m,
+            Some(m) => 2.0 * m - total,
+            None => 0.0,
+        }
+    });
+    fwht_2d(&mut coefficients);
+    Some(coefficients / ((n * n) as f64))
+}
+
+/// Four-step phase shifting: `(D₀ − D_π) + i(D_{π/2} − D_{3π/2})` is the DFT
+/// coefficient of the scene at each frequency.
+pub fn fourier(n: usize, values: &[Option<f64>]) -> Image {
+    let mut spectrum = Array2::<Complex>::from_shape_fn((n, n), |(fy, fx)| {
+        let base = 4 * (fx + fy * n);
+        let step = |k: usize| values[base + k];
+        match (step(0), step(1), step(2), step(3)) {
+            (Some(d0), Some(d1), Some(d2), Some(d3)) => {
+                Complex::new(d0 - d2, d1 - d3)
+            },
+            _ => Complex::default(),
+        }
+    });
+    fft_2d(&mut spectrum, true);
+    spectrum.mapv(|c| c.re)
+}
+
+/// Prior used to regularize compressive reconstructions.
+#[derive(Debug, Clone)]
+pub enum Regularizer {
+    /// Sparsity in a basis; suits star fields (pixel basis) and smooth
+    /// objects (DCT, wavelets).
+    Sparse(crate::lasso::Options),
+    /// Small total variation; suits extended objects like the Moon.
+    TotalVariation(crate::tv::Options),
+}
+
+impl Default for Regularizer {
+    fn default() -> Self {
+        Regularizer::Sparse(crate::lasso::Options::default())
+    }
+}
+
+/// Compressive recovery against the regenerated random masks, with the
+/// regularization weight chosen by cross-validation.
+pub fn compressive(masks: &MaskSeq, values: &[Option<f64>]) -> Image {
+    compressive_with(masks, values, &Regularizer::default())
+}
+
+pub fn compressive_with(
+    masks: &MaskSeq,
+    values: &[Option<f64>],
+    regularizer: &Regularizer,
+) -> Image {
+    let n = masks.resolution();
+    let rows: Vec<usize> =
+        (0 .. values.len()).filter(|i| values[*i].is_some()).collect();
+    let y: Array1<f64> = rows.iter().map(|i| values[*i].unwrap()).collect();
+    let a = crate::lasso::MaskOperator::new(masks, rows);
+    let solution = match regularizer {
+        Regularizer::Sparse(options) => {
+            crate::lasso::recover(&a, &y, n, options)
+        },
+        Regularizer::TotalVariation(options) => {
+            crate::tv::recover(&a, &y, n, options)
+        },
+    };
+    solution.into_shape((n, n)).unwrap()
+}
+
+/// Writes `image` as unnormalized 32-bit float greyscale.
+pub fn write_exr(image: &Image, path: impl AsRef<Path>)
+                 -> Result<(), Box<dyn Error>>
+{
+    let (height, width) = image.dim();
+    exr::prelude::write_rgba_file(path, width, height, |x, y| {
+        let intensity = image[(y, x)] as f32;
+        (intensity, intensity, intensity, 1.0f32)
+    })?;
+    Ok(())
+}
+
+// Parses `--basis <pixel|dct|haar|hadamard>`, `--tv <isotropic|anisotropic>`
+// and `--lambda <weight>` into a regularizer, returning the other arguments.
+fn parse_options(args: &[String]) -> Option<(Regularizer, Option<Duration>, Vec<String>)> {
+    use crate::lasso::Basis;
+    use crate::tv::Norm;
+    let mut regularizer = Regularizer::default();
+    let mut lambda = None;
+    let mut rebin = None;
+    let mut rest = Vec::new();
+    let mut iterator = args.iter();
+    while let Some(arg) = iterator.next() {
+        match arg.as_str() {
+            "--basis" => {
+                let basis = match iterator.next()?.as_str() {
+                    "pixel" => Basis::Pixel,
+                    "dct" => Basis::Dct,
+                    "haar" => Basis::Haar,
+                    "hadamard" => Basis::Hadamard,
+                    _ => return None,
+                };
+                regularizer = Regularizer::Sparse(crate::lasso::Options {
+                    basis,
+                    ..crate::lasso::Options::default()
+                });
+            },
+            "--tv" => {
+                let norm = match iterator.next()?.as_str() {
+                    "isotropic" => Norm::Isotropic,
+                    "anisotropic" => Norm::Anisotropic,
+                    _ => return None,
+                };
+                regularizer = Regularizer::TotalVariation(crate::tv::Options {
+                    norm,
+                    ..crate::tv::Options::default()
+                });
+            },
+            "--lambda" => {
+                lambda = Some(iterator.next()?.parse::<f64>().ok()?);
+            },
+            "--rebin" => {
+                rebin = Some(Duration::from_micros(iterator.next()?.parse().ok()?));
+            },
+            _ => rest.push(arg.clone()),
+        }
+    }
+    match &mut regularizer {
+        Regularizer::Sparse(options) => options.lambda = lambda.or(options.lambda),
+        Regularizer::TotalVariation(options) => options.lambda = lambda.or(options.lambda),
+    }
+    Some((regularizer, rebin, rest))
+}
+
+// Prints the error and exits with status 1, like the usage message.
+fn or_exit<T>(result: Result<T, Box<dyn Error>>, what: impl std::fmt::Display) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to {}: {}", what, e);
+        std::process::exit(1);
+    })
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let parsed = parse_options(&args[1 ..]);
+    if parsed.is_none() || !(1 ..= 2).contains(&parsed.as_ref().unwrap().2.len()) {
+        eprintln!("Usage: {} [--basis pixel|dct|haar|hadamard] \
+                   [--tv isotropic|anisotropic] [--lambda <weight>] \
+                   [--rebin <microseconds>] <capture.cbor> [output.exr]", args[0]);
+        std::process::exit(1);
+    }
+    let (regularizer, rebin, paths) = parsed.unwrap();
+    let input = Path::new(&paths[0]);
+    let output = paths.get(1).map(|s| Path::new(s).to_path_buf())
+        .unwrap_or_else(|| input.with_extension("exr"));
+
+    let mut capture = or_exit(load(input), format_args!("load {}", input.display()));
+    println!("Loaded {:?} capture with {} windows",
+             capture.masks, capture.pulses.len());
+    if let Some(length) = rebin {
+        // Windows of different frames may have seen different masks.
+        capture.pulses = crate::gpio::rebin(&capture.pulses, length, |a, b| a.0 == b.0);
+        println!("Rebinned to {} windows", capture.pulses.len());
+    }
+    if let (Some(first), Some(last)) = (capture.angles.first(), capture.angles.last()) {
+        println!("Masks derotated by {:.3}°", (last - first).to_degrees());
+    }
+    let (image, errors) = or_exit(
+        reconstruct_calibrated(&capture, &regularizer, &capture_profile(&capture)),
+        "reconstruct the image");
+    or_exit(write_exr(&image, &output), format_args!("write {}", output.display()));
+    if let Some(errors) = errors {
+        let path = output.with_file_name(format!(
+            "{}-error.exr", output.file_stem().unwrap().to_string_lossy()));
+        or_exit(write_exr(&errors, &path), format_args!("write {}", path.display()));
+        println!("Wrote standard errors to {}", path.display());
+    }
+    println!("Minimum value: {}", image.fold(f64::INFINITY, |a, b| a.min(*b)));
+    println!("Maximum value: {}", image.fold(f64::NEG_INFINITY, |a, b| a.max(*b)));
+    println!("Wrote to {}", output.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ideal single-pixel detector: the scene weighted by each mask.
+    fn simulate(masks: &MaskSeq, scene: &Image) -> Vec<Option<f64>> {
+        (0 .. masks.len()).map(|i| {
+            let pattern = masks.pattern(i);
+            Some(pattern.enumerate_pixels()
+                 .map(|(x, y, p)| {
+                     (p.0[0] as f64) / 255.0 * scene[(y as usize, x as usize)]
+                 })
+                 .sum())
+        }).collect()
+    }
+
+    fn scene(n: usize) -> Image {
+        Image::from_shape_fn((n, n), |(y, x)| ((3 * x + 5 * y) % 7) as f64)
+    }
+
+    fn max_error(a: &Image, b: &Image) -> f64 {
+        (a - b).fold(0.0, |m, v| f64::max(m, v.abs()))
+    }
+
+    #[test]
+    fn differential_pairs_cancel_drift() {
+        let masks = MaskSeq::Hadamard(8);
+        let truth = scene(8);
+        let plain = simulate(&masks, &truth);
+        let mut values = Vec::new();
+        for (i, value) in plain.iter().enumerate() {
+            // Background rising pair by pair, as at dawn.
+            let drift = 0.7 * i as f64;
+            values.push(Some(value.unwrap() + drift));
+            values.push(Some(truth.sum() - value.unwrap() + drift));
+        }
+        // Drift is left only as the same offset on every value.
+        let offsets: Vec<f64> = combine_pairs(&values).iter().zip(&plain)
+            .map(|(c, p)| c.unwrap() - p.unwrap())
+            .collect();
+        assert!(offsets.iter().all(|o| (o - offsets[0]).abs() < 1e-9));
+    }
+
+    #[test]
+    fn hadamard_roundtrip() {
+        let masks = MaskSeq::Hadamard(16);
+        let truth = scene(16);
+        let image = reconstruct_from(&masks, &simulate(&masks, &truth),
+                                     &Regularizer::default()).unwrap();
+        assert!(max_error(&image, &truth) < 1e-9);
+    }
+
+    #[test]
+    fn fourier_roundtrip() {
+        let masks = MaskSeq::Fourier(12);
+        let truth = scene(12);
+        let image = reconstruct_from(&masks, &simulate(&masks, &truth),
+                                     &Regularizer::default()).unwrap();
+        // Masks are quantized to 8 bits, so this is only approximate.
+        assert!(max_error(&image, &truth) < 0.1);
+    }
+
+    #[test]
+    fn hadamard_needs_the_total() {
+        let masks = MaskSeq::Hadamard(4);
+        let mut values = simulate(&masks, &scene(4));
+        values[0] = None;
+        assert!(reconstruct_from(&masks, &values, &Regularizer::default()).is_err());
+    }
+
+    #[test]
+    fn flat_field_removes_vignetting() {
+        let masks = MaskSeq::Hadamard(16);
+        // Light falls off away from the middle and is cut off at the corners.
+        let vignetting = Image::from_shape_fn((16, 16), |(y, x)| {
+            let r2 = (x as f64 - 7.5).powi(2) + (y as f64 - 7.5).powi(2);
+            if r2 < 64.0 { 1.0 - r2 / 128.0 } else { 0.0 }
+        });
+        let flat = reconstruct_from(
+            &masks, &simulate(&masks, &(&vignetting * 50.0)), &Regularizer::default()).unwrap();
+        let flat = FlatField::measure(&flat).unwrap();
+        let truth = scene(16);
+        let image = reconstruct_from(
+            &masks, &simulate(&masks, &(&truth * &vignetting)), &Regularizer::default()).unwrap();
+        let image = flatten(&image, &flat);
+        let ratio = Image::from_shape_fn((16, 16), |index| {
+            if vignetting[index] > 0.0 && truth[index] > 0.0 { image[index] / truth[index] } else { 0.0 }
+        });
+        let ratios: Vec<f64> = ratio.iter().cloned().filter(|r| *r > 0.0).collect();
+        assert!(ratios.iter().all(|r| (r - ratios[0]).abs() < 1e-6), "{:?}", ratios);
+        assert!(image.iter().zip(&vignetting).all(|(v, g)| *g > 0.0 || *v == 0.0));
+    }
+
+    #[test]
+    fn propagated_errors_match_simulated_noise() {
+        let masks = MaskSeq::Hadamard(4);
+        let truth = scene(4);
+        let clean = simulate(&masks, &truth);
+        let (sigma, common) = (0.5, 0.3);
+        let errors = vec![Some(sigma); clean.len()];
+        let predicted = propagate_errors(&masks, &errors, common).unwrap();
+
+        // Box-Muller on a fixed LCG, so the test is deterministic.
+        let mut state = 12345u64;
+        let mut uniform = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+        };
+        let mut gaussian = || {
+            (-2.0 * uniform().ln()).sqrt() * (2.0 * std::f64::consts::PI * uniform()).cos()
+        };
+        let trials = 4000;
+        let mut variance = Image::zeros((4, 4));
+        for _ in 0 .. trials {
+            let offset = common * gaussian();
+            let noisy: Vec<Option<f64>> = clean.iter()
+                .map(|v| Some(v.unwrap() + offset + sigma * gaussian()))
+                .collect();
+            let error = reconstruct_from(&masks, &noisy, &Regularizer::default()).unwrap() - &truth;
+            variance += &error.mapv(|e| e * e / trials as f64);
+        }
+        for (simulated, predicted) in variance.iter().zip(&predicted) {
+            assert!((simulated.sqrt() / predicted - 1.0).abs() < 0.1,
+                    "{} vs {}", simulated.sqrt(), predicted);
+        }
+    }
+
+    #[test]
+    fn random_recovers_sparse_scene() {
+        let masks = MaskSeq::Random { resolution: 16, count: 128, seed: 7 };
+        let mut truth = Image::zeros((16, 16));
+        truth[(3, 4)] = 10.0;
+        truth[(12, 9)] = 6.0;
+        truth[(8, 14)] = 8.0;
+        let image = reconstruct_from(&masks, &simulate(&masks, &truth),
+                                     &Regularizer::default()).unwrap();
+        assert!(max_error(&image, &truth) < 1.0);
+    }
+}
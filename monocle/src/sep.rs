@@ -6,17 +6,22 @@ use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
 pub type Mask = imageproc::definitions::Image<image::Luma<u8>>;
 
 pub mod aperture {
+    /// Semi-major axis `a`, semi-minor axis `b`, and the angle `theta` of the
+    /// major axis from the x axis, in radians.
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub struct EllipseAxes {
         pub a: f64,
         pub b: f64,
         pub theta: f64,
     }
 
+    /// Axes of the ellipse `cxx x² + cyy y² + cxy xy = 1`, as in SEP's
+    /// `sep_ellipse_axes`; `None` if the coefficients aren't an ellipse.
     pub fn ellipse_axes(cxx: f64, cyy: f64, cxy: f64) -> Option<EllipseAxes> {
         let p = cxx + cyy;
         let q = cxx - cyy;
         let t = f64::sqrt(q * q + cxy * cxy);
-        if ((cxx * cyy - ((cxy * cxy) / 4.0)) <= 0) || (p <= 0) {
+        if ((cxx * cyy - ((cxy * cxy) / 4.0)) <= 0.0) || (p <= 0.0) {
             return None;
         }
         let a = f64::sqrt(2.0 / (p - t));
@@ -26,12 +31,33 @@ pub mod aperture {
         } else {
             f64::atan(cxy / q) / 2.0
         };
-        if (cxx > cyy) {
+        if cxx > cyy {
             theta += std::f64::consts::FRAC_PI_2;
         }
-        if (theta > std::f64::consts::FRAC_PI_2) {
+        if theta > std::f64::consts::FRAC_PI_2 {
             theta -= std::f64::consts::PI;
         }
-        return EllipseAxes { a, b, theta };
+        Some(EllipseAxes { a, b, theta })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn recovers_axes_of_rotated_ellipse() {
+            let (a, b) = (5.0, 2.0);
+            for theta in [-1.2, -0.3, 0.0, 0.4, 1.1] {
+                let (sin, cos) = f64::sin_cos(theta);
+                let cxx = cos * cos / (a * a) + sin * sin / (b * b);
+                let cyy = sin * sin / (a * a) + cos * cos / (b * b);
+                let cxy = 2.0 * sin * cos * (1.0 / (a * a) - 1.0 / (b * b));
+                let axes = ellipse_axes(cxx, cyy, cxy).unwrap();
+                assert!((axes.a - a).abs() < 1e-9);
+                assert!((axes.b - b).abs() < 1e-9);
+                assert!((axes.theta - theta).abs() < 1e-9, "{} != {}", axes.theta, theta);
+            }
+            assert_eq!(ellipse_axes(1.0, -1.0, 0.0), None);
+        }
     }
 }
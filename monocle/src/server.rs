@@ -6,6 +6,7 @@ use std::sync::{Arc, Mutex};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use once_cell::sync::Lazy;
 use crate::api::*;
+use crate::profile::{Calibrated, DarkRate, FlatField, Profile};
 
 #[derive(Clone)]
 struct TcpLogger {
@@ -54,6 +55,82 @@ impl log::Log for TcpLogger {
 //     stream: Arc::new(Mutex::new(None)),
 // });
 
+// Lists the connectors of the card, first switching to `requested` if one
+// of them offers its mode.
+fn displays(requested: Option<crate::display::DisplayConfig>) -> DisplaysResp {
+    let card = requested.as_ref()
+        .map_or_else(|| crate::lcd::config().card, |config| config.card.clone());
+    match crate::lcd::connectors(&card) {
+        Ok(connectors) => {
+            let error = requested.and_then(|config| {
+                match config.choose(&connectors) {
+                    Ok(_) => {
+                        crate::lcd::configure(config);
+                        None
+                    },
+                    Err(e) => Some(e.to_string()),
+                }
+            });
+            DisplaysResp { current: crate::lcd::config(), connectors, error }
+        },
+        Err(e) => DisplaysResp {
+            current: crate::lcd::config(),
+            connectors: Vec::new(),
+            error: Some(format!("Couldn't probe {}: {}", card, e)),
+        },
+    }
+}
+
+// Saves the reconstruction of the first capture that isn't of the dark rate,
+// less the dark rate, as the flat field.
+fn flat_field(captures: &[Capture]) -> Result<FlatField, String> {
+    let capture = captures.iter()
+        .find(|capture| !matches!(capture.masks, MaskSeq::Dark { .. }))
+        .ok_or("No capture to take the flat field from")?;
+    let profile = Profile { flat_field: None, ..crate::profile::current() };
+    let (image, _) = crate::reconstruct::reconstruct_calibrated(
+        capture, &Default::default(), &profile).map_err(|e| e.to_string())?;
+    let flat = FlatField::measure(&image).ok_or("Flat field capture saw no light")?;
+    crate::profile::Store::pi()
+        .update(|profile| profile.flat_field = Some(Calibrated::now(flat.clone())))
+        .map_err(|e| e.to_string())?;
+    Ok(flat)
+}
+
+// Frames of the mode captures are displayed in that last `seconds`.
+fn dark_frames(seconds: f64) -> Result<usize, String> {
+    let timing = crate::lcd::expected_timing().ok_or_else(|| {
+        format!("The modulator has no mode matching {}", crate::lcd::config())
+    })?;
+    Ok((seconds * timing.refresh_rate()).round() as usize)
+}
+
+// Saves the windows of a dark capture as the dark rate.
+fn dark_rate(capture: &Capture) -> Result<DarkRate, String> {
+    let readings = crate::reconstruct::readings_by_mask(capture);
+    let dark = DarkRate::measure(readings.values().flatten().cloned())
+        .ok_or("Dark capture recorded too few windows")?;
+    crate::profile::Store::pi()
+        .update(|profile| profile.dark = Some(Calibrated::now(dark)))
+        .map_err(|e| e.to_string())?;
+    Ok(dark)
+}
+
+fn profile(req: ProfileReq) -> ProfileResp {
+    let store = crate::profile::Store::pi();
+    let result = match req {
+        ProfileReq::Fetch(Some(version)) => store.get(version),
+        ProfileReq::Fetch(None) => store.latest(),
+        ProfileReq::Update(update) => store.update(|profile| profile.merge(update)),
+        ProfileReq::Rollback(version) => store.rollback(version),
+    };
+    let versions = store.versions().unwrap_or_default();
+    match result {
+        Ok(profile) => ProfileResp { profile: Some(profile), versions, error: None },
+        Err(e) => ProfileResp { profile: None, versions, error: Some(e.to_string()) },
+    }
+}
+
 fn handle_client(mut stream: TcpStream) {
     loop {
         let mut data = [0 as u8; 4];
@@ -70,10 +147,61 @@ fn handle_client(mut stream: TcpStream) {
         // std::thread::sleep(std::time::Duration::from_millis(60000));
 
         let response = match request {
-            Request::TakePicture(_) => {
-                Response::TakePicture(TakePictureResp {
-                    pulses: vec![crate::capture::scanbox()],
-                })
+            Request::TakePicture(req) => {
+                let settings = crate::capture::Settings {
+                    differential: req.differential,
+                    derotate: req.derotate,
+                    requeue_dropped: req.requeue_dropped,
+                    preload: req.preload,
+                    window: req.window,
+                };
+                let mut masks = req.masks;
+                let mut errors = Vec::new();
+                if let Some(seconds) = req.dark {
+                    match dark_frames(seconds) {
+                        Ok(frames) => masks.push(MaskSeq::Dark { frames }),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                // Sequences captured before a failure are still returned.
+                let mut captures = Vec::new();
+                for seq in &masks {
+                    if !errors.is_empty() {
+                        break;
+                    }
+                    match crate::capture::capture(seq, &settings) {
+                        Ok(capture) => captures.push(capture),
+                        Err(e) => {
+                            log::error!("Capture of {:?} failed: {}", seq, e);
+                            errors.push(e.to_string());
+                            break;
+                        },
+                    }
+                }
+                let mut resp = TakePictureResp {
+                    captures,
+                    flat_field: None,
+                    dark: None,
+                    error: None,
+                };
+                if req.flat_field {
+                    match flat_field(&resp.captures) {
+                        Ok(flat) => resp.flat_field = Some(flat),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                let dark = resp.captures.iter()
+                    .find(|capture| matches!(capture.masks, MaskSeq::Dark { .. }));
+                if let Some(capture) = dark {
+                    match dark_rate(capture) {
+                        Ok(dark) => resp.dark = Some(dark),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                if !errors.is_empty() {
+                    resp.error = Some(errors.join("; "));
+                }
+                Response::TakePicture(resp)
             },
             Request::GoTo(_) => {
                 Response::GoTo(GoToResp {})
@@ -88,22 +216,54 @@ fn handle_client(mut stream: TcpStream) {
                 })
             },
             Request::Calibrate(CalibrateReq::Latency) => {
-                std::thread::spawn(move || {
-                    let _ = crate::calibrate::latency();
-                });
-                Response::Calibrate(CalibrateResp {})
+                Response::Calibrate(match crate::calibrate::latency() {
+                    Ok(latency) => CalibrateResp::Latency(latency),
+                    Err(e) => CalibrateResp::Failed(e.to_string()),
+                })
+            },
+            Request::Calibrate(CalibrateReq::Aperture) => {
+                Response::Calibrate(match crate::calibrate::aperture() {
+                    Ok(aperture) => CalibrateResp::Aperture(aperture),
+                    Err(e) => CalibrateResp::Failed(e.to_string()),
+                })
+            },
+            Request::Calibrate(CalibrateReq::Response) => {
+                Response::Calibrate(match crate::calibrate::response() {
+                    Ok(curves) => CalibrateResp::Response(curves),
+                    Err(e) => CalibrateResp::Failed(e.to_string()),
+                })
             },
             Request::Calibrate(CalibrateReq::Flicker) => {
-                // std::thread::spawn(move || {
-                    let _ = crate::calibrate::flicker();
-                // });
-                Response::Calibrate(CalibrateResp {})
+                Response::Calibrate(match crate::calibrate::flicker() {
+                    Ok(()) => CalibrateResp::Done,
+                    Err(e) => CalibrateResp::Failed(e.to_string()),
+                })
             },
+            // Cutoffs stay up after responding, so their errors are only logged.
             Request::Calibrate(CalibrateReq::Cutoff(x, y, dist)) => {
                 std::thread::spawn(move || {
-                    let _ = crate::calibrate::circular_cutoff(x, y, dist);
+                    if let Err(e) = crate::calibrate::circular_cutoff(x, y, dist) {
+                        log::error!("Circular cutoff failed: {}", e);
+                    }
                 });
-                Response::Calibrate(CalibrateResp {})
+                Response::Calibrate(CalibrateResp::Started)
+            },
+            Request::Calibrate(CalibrateReq::EllipticalCutoff(x, y, cxx, cyy, cxy)) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = crate::calibrate::elliptical_cutoff(x, y, cxx, cyy, cxy) {
+                        log::error!("Elliptical cutoff failed: {}", e);
+                    }
+                });
+                Response::Calibrate(CalibrateResp::Started)
+            },
+            Request::Profile(req) => {
+                Response::Profile(profile(req))
+            },
+            Request::Displays => {
+                Response::Displays(displays(None))
+            },
+            Request::ConfigureDisplay(config) => {
+                Response::Displays(displays(Some(config)))
             },
             Request::Reboot => {
                 nix::unistd::close(stream.as_raw_fd()).unwrap();
@@ -253,6 +413,13 @@ pub fn main() {
     //     }
     // });
 
+    if let Ok(spec) = std::env::var("display") {
+        match spec.parse() {
+            Ok(config) => crate::lcd::configure(config),
+            Err(e) => log::error!("Ignoring display variable: {}", e),
+        }
+    }
+
     let listener = TcpListener::bind("0.0.0.0:3333").unwrap();
     // accept connections and process them, spawning a new thread for each one
     println!("Server listening on port 3333");
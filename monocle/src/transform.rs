@@ -0,0 +1,537 @@
+use std::ops::{Add, Mul, Sub};
+use ndarray::{Array1, Array2, Axis};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    /// `e^{iθ}`
+    pub fn cis(theta: f64) -> Self {
+        Complex { re: f64::cos(theta), im: f64::sin(theta) }
+    }
+
+    pub fn conj(self) -> Self {
+        Complex { re: self.re, im: -self.im }
+    }
+
+    pub fn scale(self, factor: f64) -> Self {
+        Complex { re: self.re * factor, im: self.im * factor }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+// In-place iterative radix-2 Cooley-Tukey; `data.len()` must be a power of two.
+fn fft_radix2(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1 .. n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j ^= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let step = Complex::cis(sign * 2.0 * std::f64::consts::PI / (length as f64));
+        for start in (0 .. n).step_by(length) {
+            let mut w = Complex::new(1.0, 0.0);
+            for k in 0 .. length / 2 {
+                let u = data[start + k];
+                let v = data[start + k + length / 2] * w;
+                data[start + k] = u + v;
+                data[start + k + length / 2] = u - v;
+                w = w * step;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+// Bluestein's chirp-z algorithm, for lengths that aren't a power of two.
+fn fft_bluestein(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    let m = (2 * n - 1).next_power_of_two();
+    let sign = if inverse { 1.0 } else { -1.0 };
+    // Reduce k² modulo 2n before scaling so the chirp stays accurate for large n.
+    let chirp: Vec<Complex> = (0 .. n).map(|k| {
+        let k_squared = ((k as u64 * k as u64) % (2 * n as u64)) as f64;
+        Complex::cis(sign * std::f64::consts::PI * k_squared / (n as f64))
+    }).collect();
+    let mut a = vec![Complex::default(); m];
+    let mut b = vec![Complex::default(); m];
+    for k in 0 .. n {
+        a[k] = data[k] * chirp[k];
+    }
+    b[0] = chirp[0].conj();
+    for k in 1 .. n {
+        b[k] = chirp[k].conj();
+        b[m - k] = chirp[k].conj();
+    }
+    fft_radix2(&mut a, false);
+    fft_radix2(&mut b, false);
+    for k in 0 .. m {
+        a[k] = a[k] * b[k];
+    }
+    fft_radix2(&mut a, true);
+    for k in 0 .. n {
+        data[k] = (a[k] * chirp[k]).scale(1.0 / (m as f64));
+    }
+}
+
+/// Unnormalized discrete Fourier transform of any length, in place. The
+/// inverse direction uses `e^{+iθ}` and is *not* divided by `n`.
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    if n <= 1 {
+        return;
+    }
+    if n.is_power_of_two() {
+        fft_radix2(data, inverse);
+    } else {
+        fft_bluestein(data, inverse);
+    }
+}
+
+/// Separable 2D FFT over both axes; the inverse is normalized by the number
+/// of elements so that `fft_2d(fft_2d(x, false), true) == x`.
+pub fn fft_2d(data: &mut Array2<Complex>, inverse: bool) {
+    for axis in [Axis(0), Axis(1)] {
+        for mut lane in data.lanes_mut(axis) {
+            let mut buffer: Vec<Complex> = lane.iter().cloned().collect();
+            fft(&mut buffer, inverse);
+            for (target, value) in lane.iter_mut().zip(buffer) {
+                *target = value;
+            }
+        }
+    }
+    if inverse {
+        let factor = 1.0 / (data.len() as f64);
+        data.mapv_inplace(|c| c.scale(factor));
+    }
+}
+
+// Applies `transform` to every row and then every column of `data`.
+fn separable(data: &mut Array2<f64>, transform: impl Fn(&mut [f64])) {
+    for axis in [Axis(1), Axis(0)] {
+        let mut buffer = Vec::new();
+        for mut lane in data.lanes_mut(axis) {
+            buffer.clear();
+            buffer.extend(lane.iter().cloned());
+            transform(&mut buffer);
+            for (target, value) in lane.iter_mut().zip(&buffer) {
+                *target = *value;
+            }
+        }
+    }
+}
+
+/// Orthonormal DCT-II in `O(n log n)`, in place, via Makhoul's reordering
+/// into a single complex FFT of the same length.
+pub fn dct(data: &mut [f64]) {
+    let n = data.len();
+    if n == 0 {
+        return;
+    }
+    let mut v: Vec<Complex> = (0 .. n).map(|k| {
+        let i = if k < (n + 1) / 2 { 2 * k } else { 2 * (n - k) - 1 };
+        Complex::new(data[i], 0.0)
+    }).collect();
+    fft(&mut v, false);
+    let size = n as f64;
+    for k in 0 .. n {
+        let twiddle = Complex::cis(-std::f64::consts::PI * (k as f64) / (2.0 * size));
+        let scale = if k == 0 { f64::sqrt(1.0 / size) } else { f64::sqrt(2.0 / size) };
+        data[k] = (v[k] * twiddle).re * scale;
+    }
+}
+
+/// Orthonormal DCT-III, the inverse of [`dct`].
+pub fn idct(data: &mut [f64]) {
+    let n = data.len();
+    if n == 0 {
+        return;
+    }
+    let size = n as f64;
+    let unscaled = |k: usize| {
+        if k == 0 {
+            data[0] * f64::sqrt(size)
+        } else if k < n {
+            data[k] * f64::sqrt(size / 2.0)
+        } else {
+            0.0
+        }
+    };
+    let mut v: Vec<Complex> = (0 .. n).map(|k| {
+        let twiddle = Complex::cis(std::f64::consts::PI * (k as f64) / (2.0 * size));
+        Complex::new(unscaled(k), -unscaled(n - k)) * twiddle
+    }).collect();
+    v[0] = Complex::new(unscaled(0), 0.0);
+    fft(&mut v, true);
+    for k in 0 .. n {
+        let i = if k < (n + 1) / 2 { 2 * k } else { 2 * (n - k) - 1 };
+        data[i] = v[k].re / size;
+    }
+}
+
+/// Separable orthonormal 2D DCT-II.
+pub fn dct_2d(data: &mut Array2<f64>) {
+    separable(data, dct);
+}
+
+/// Separable orthonormal 2D DCT-III, the inverse of [`dct_2d`].
+pub fn idct_2d(data: &mut Array2<f64>) {
+    separable(data, idct);
+}
+
+/// Unnormalized fast Walsh-Hadamard transform in natural (Sylvester) order.
+/// Applying it twice multiplies the input by `data.len()`.
+pub fn fwht(data: &mut [f64]) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "FWHT length {} is not a power of two", n);
+    let mut half = 1;
+    while half < n {
+        for start in (0 .. n).step_by(2 * half) {
+            for k in start .. start + half {
+                let u = data[k];
+                let v = data[k + half];
+                data[k] = u + v;
+                data[k + half] = u - v;
+            }
+        }
+        half <<= 1;
+    }
+}
+
+/// Unnormalized separable 2D Walsh-Hadamard transform.
+pub fn fwht_2d(data: &mut Array2<f64>) {
+    separable(data, fwht);
+}
+
+// One orthonormal Haar analysis step on `data`: averages to the front half,
+// details to the back half.
+fn haar_step(data: &mut [f64]) {
+    let half = data.len() / 2;
+    let input = data.to_vec();
+    for i in 0 .. half {
+        let (a, b) = (input[2 * i], input[2 * i + 1]);
+        data[i] = (a + b) * std::f64::consts::FRAC_1_SQRT_2;
+        data[half + i] = (a - b) * std::f64::consts::FRAC_1_SQRT_2;
+    }
+}
+
+fn inverse_haar_step(data: &mut [f64]) {
+    let half = data.len() / 2;
+    let input = data.to_vec();
+    for i in 0 .. half {
+        let (s, d) = (input[i], input[half + i]);
+        data[2 * i] = (s + d) * std::f64::consts::FRAC_1_SQRT_2;
+        data[2 * i + 1] = (s - d) * std::f64::consts::FRAC_1_SQRT_2;
+    }
+}
+
+/// Sizes of the approximation band at each level of the 2D Haar pyramid,
+/// starting from the full array and halving while both sides stay even.
+pub fn haar_levels(height: usize, width: usize) -> Vec<(usize, usize)> {
+    let mut levels = Vec::new();
+    let (mut h, mut w) = (height, width);
+    while h >= 2 && w >= 2 && h % 2 == 0 && w % 2 == 0 {
+        levels.push((h, w));
+        h /= 2;
+        w /= 2;
+    }
+    levels
+}
+
+fn haar_2d_level(data: &mut Array2<f64>, (h, w): (usize, usize), inverse: bool) {
+    let step = if inverse { inverse_haar_step } else { haar_step };
+    let mut buffer = vec![0.0; usize::max(h, w)];
+    let mut pass = |data: &mut Array2<f64>, rows: bool| {
+        let (outer, inner) = if rows { (h, w) } else { (w, h) };
+        for i in 0 .. outer {
+            for j in 0 .. inner {
+                buffer[j] = if rows { data[(i, j)] } else { data[(j, i)] };
+            }
+            step(&mut buffer[.. inner]);
+            for j in 0 .. inner {
+                if rows { data[(i, j)] = buffer[j] } else { data[(j, i)] = buffer[j] }
+            }
+        }
+    };
+    if inverse {
+        pass(data, false);
+        pass(data, true);
+    } else {
+        pass(data, true);
+        pass(data, false);
+    }
+}
+
+/// Orthonormal multi-level 2D Haar wavelet transform (the "nonstandard"
+/// pyramid), in place. Each level transforms rows then columns of the current
+/// approximation band in the upper left corner.
+pub fn haar_2d(data: &mut Array2<f64>) {
+    let (height, width) = data.dim();
+    for level in haar_levels(height, width) {
+        haar_2d_level(data, level, false);
+    }
+}
+
+/// Inverse of [`haar_2d`].
+pub fn inverse_haar_2d(data: &mut Array2<f64>) {
+    let (height, width) = data.dim();
+    for level in haar_levels(height, width).into_iter().rev() {
+        haar_2d_level(data, level, true);
+    }
+}
+
+/// Orthonormal 2D bases an image can be expanded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Basis {
+    /// The image itself, e.g. for sparse star fields.
+    Pixel,
+    /// DCT-II, for smooth extended objects.
+    Dct,
+    /// Multi-level Haar wavelets, for piecewise flat objects.
+    Haar,
+    /// Walsh-Hadamard, scaled to be orthonormal; sides must be powers of two.
+    Hadamard,
+}
+
+impl Basis {
+    /// Image → coefficients (`Ψᵀx`), in place.
+    pub fn analyze_2d(&self, data: &mut Array2<f64>) {
+        match self {
+            Basis::Pixel => {},
+            Basis::Dct => dct_2d(data),
+            Basis::Haar => haar_2d(data),
+            Basis::Hadamard => {
+                fwht_2d(data);
+                *data /= f64::sqrt(data.len() as f64);
+            },
+        }
+    }
+
+    /// Coefficients → image (`Ψc`), in place.
+    pub fn synthesize_2d(&self, data: &mut Array2<f64>) {
+        match self {
+            Basis::Pixel => {},
+            Basis::Dct => idct_2d(data),
+            Basis::Haar => inverse_haar_2d(data),
+            // The scaled transform is its own inverse.
+            Basis::Hadamard => self.analyze_2d(data),
+        }
+    }
+
+    /// [`Basis::analyze_2d`] on a row-major flattened `n × n` image.
+    pub fn analyze(&self, n: usize, image: &Array1<f64>) -> Array1<f64> {
+        let mut data = image.clone().into_shape((n, n)).unwrap();
+        self.analyze_2d(&mut data);
+        Array1::from_iter(data.into_iter())
+    }
+
+    /// [`Basis::synthesize_2d`] on row-major flattened `n × n` coefficients.
+    pub fn synthesize(&self, n: usize, coefficients: &Array1<f64>) -> Array1<f64> {
+        let mut data = coefficients.clone().into_shape((n, n)).unwrap();
+        self.synthesize_2d(&mut data);
+        Array1::from_iter(data.into_iter())
+    }
+}
+
+/// A linear map that can be applied (and transposed) without necessarily
+/// materializing its matrix.
+pub trait LinearOperator {
+    fn rows(&self) -> usize;
+    fn cols(&self) -> usize;
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64>;
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64>;
+}
+
+impl LinearOperator for Array2<f64> {
+    fn rows(&self) -> usize {
+        self.nrows()
+    }
+
+    fn cols(&self) -> usize {
+        self.ncols()
+    }
+
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64> {
+        self.dot(x)
+    }
+
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64> {
+        self.t().dot(y)
+    }
+}
+
+/// Synthesis `Ψ` in `basis` as a linear operator on flattened `n × n`
+/// arrays. It's orthonormal, so its adjoint is analysis.
+pub struct Synthesis {
+    pub basis: Basis,
+    pub resolution: usize,
+}
+
+impl LinearOperator for Synthesis {
+    fn rows(&self) -> usize {
+        self.resolution * self.resolution
+    }
+
+    fn cols(&self) -> usize {
+        self.resolution * self.resolution
+    }
+
+    fn apply(&self, x: &Array1<f64>) -> Array1<f64> {
+        self.basis.synthesize(self.resolution, x)
+    }
+
+    fn adjoint(&self, y: &Array1<f64>) -> Array1<f64> {
+        self.basis.analyze(self.resolution, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_dft(data: &[Complex]) -> Vec<Complex> {
+        let n = data.len();
+        (0 .. n).map(|k| {
+            (0 .. n).fold(Complex::default(), |sum, j| {
+                let theta = -2.0 * std::f64::consts::PI * ((j * k) as f64) / (n as f64);
+                sum + data[j] * Complex::cis(theta)
+            })
+        }).collect()
+    }
+
+    fn signal(n: usize) -> Vec<f64> {
+        (0 .. n).map(|i| f64::sin(0.3 * (i as f64)) + ((i * 7) % 5) as f64).collect()
+    }
+
+    fn image(h: usize, w: usize) -> Array2<f64> {
+        Array2::from_shape_fn((h, w), |(y, x)| ((3 * x + 5 * y * y) % 11) as f64)
+    }
+
+    fn max_difference(a: &Array2<f64>, b: &Array2<f64>) -> f64 {
+        (a - b).fold(0.0, |m, v| f64::max(m, v.abs()))
+    }
+
+    #[test]
+    fn fft_matches_naive_dft() {
+        for n in [1, 2, 8, 12, 17, 250] {
+            let input: Vec<Complex> = signal(n).iter().zip(signal(n + 1).iter().skip(1))
+                .map(|(re, im)| Complex::new(*re, *im)).collect();
+            let mut output = input.clone();
+            fft(&mut output, false);
+            for (a, b) in output.iter().zip(naive_dft(&input)) {
+                assert!((*a - b).re.abs() < 1e-8 && (*a - b).im.abs() < 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn dct_matches_definition() {
+        for n in [1, 2, 7, 16, 250] {
+            let input = signal(n);
+            let mut output = input.clone();
+            dct(&mut output);
+            let naive = crate::lasso::dct_1d(&Array1::from(input));
+            for (k, value) in output.iter().enumerate() {
+                let scale = if k == 0 { 1.0 / n as f64 } else { 2.0 / n as f64 };
+                assert!((value - naive[k] * f64::sqrt(scale)).abs() < 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn dct_roundtrip() {
+        for n in [1, 2, 3, 10, 64, 250] {
+            let input = signal(n);
+            let mut data = input.clone();
+            dct(&mut data);
+            idct(&mut data);
+            for (a, b) in data.iter().zip(&input) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn fft_2d_roundtrip() {
+        let input = image(12, 20).mapv(|v| Complex::new(v, -v));
+        let mut data = input.clone();
+        fft_2d(&mut data, false);
+        fft_2d(&mut data, true);
+        let error = (&data - &input).iter().fold(0.0, |m, c| f64::max(m, c.re.abs() + c.im.abs()));
+        assert!(error < 1e-9);
+    }
+
+    #[test]
+    fn fwht_is_an_involution_up_to_scale() {
+        let input = image(16, 8);
+        let mut data = input.clone();
+        fwht_2d(&mut data);
+        fwht_2d(&mut data);
+        assert!(max_difference(&(data / 128.0), &input) < 1e-9);
+    }
+
+    #[test]
+    fn bases_roundtrip_and_preserve_energy() {
+        let input = image(32, 32);
+        for basis in [Basis::Pixel, Basis::Dct, Basis::Haar, Basis::Hadamard] {
+            let mut data = input.clone();
+            basis.analyze_2d(&mut data);
+            let energy = |a: &Array2<f64>| a.fold(0.0, |s, v| s + v * v);
+            assert!((energy(&data) - energy(&input)).abs() < 1e-6 * energy(&input));
+            basis.synthesize_2d(&mut data);
+            assert!(max_difference(&data, &input) < 1e-9, "{:?}", basis);
+        }
+    }
+
+    #[test]
+    fn rectangular_transforms_roundtrip() {
+        let input = image(24, 10);
+        let mut data = input.clone();
+        dct_2d(&mut data);
+        idct_2d(&mut data);
+        assert!(max_difference(&data, &input) < 1e-9);
+        haar_2d(&mut data);
+        inverse_haar_2d(&mut data);
+        assert!(max_difference(&data, &input) < 1e-9);
+    }
+}
@@ -0,0 +1,254 @@
+use ndarray::{Array1, Array2, Array3, s};
+use crate::transform::LinearOperator;
+use crate::lasso::{TOLERANCE, cross_validate_with, lambda_grid,
+                   lambda_max, spectral_norm_squared};
+
+// minimize ½‖Ax − y‖₂² + λ TV(x)
+//
+// solved with the primal-dual algorithm of Chambolle & Pock (2011), splitting
+// K = [A; ∇] so that both proximal steps are closed form.
+
+/// How the horizontal and vertical differences at a pixel are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Norm {
+    /// `|∂x| + |∂y|`; cheaper, but favours axis-aligned edges.
+    Anisotropic,
+    /// `√(∂x² + ∂y²)`; rotation invariant, better for round objects.
+    Isotropic,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub norm: Norm,
+    /// Weight of the TV term; `None` picks one by cross-validation.
+    pub lambda: Option<f64>,
+    pub iterations: usize,
+    /// Clamp the image to be nonnegative, as photon counts must be.
+    pub nonnegative: bool,
+    /// Number of folds and of candidate weights tried by cross-validation.
+    pub folds: usize,
+    pub candidates: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            norm: Norm::Isotropic,
+            lambda: None,
+            iterations: 1000,
+            nonnegative: true,
+            folds: 5,
+            candidates: 8,
+        }
+    }
+}
+
+/// Forward differences with a zero (Neumann) boundary; `[0]` is along x and
+/// `[1]` along y.
+pub fn gradient(image: &Array2<f64>) -> Array3<f64> {
+    let (h, w) = image.dim();
+    let mut result = Array3::<f64>::zeros((2, h, w));
+    for y in 0 .. h {
+        for x in 0 .. w {
+            if x + 1 < w {
+                result[(0, y, x)] = image[(y, x + 1)] - image[(y, x)];
+            }
+            if y + 1 < h {
+                result[(1, y, x)] = image[(y + 1, x)] - image[(y, x)];
+            }
+        }
+    }
+    result
+}
+
+/// Negative adjoint of [`gradient`].
+pub fn divergence(field: &Array3<f64>) -> Array2<f64> {
+    let (_, h, w) = field.dim();
+    let mut result = Array2::<f64>::zeros((h, w));
+    for y in 0 .. h {
+        for x in 0 .. w {
+            let mut value = 0.0;
+            if x + 1 < w {
+                value += field[(0, y, x)];
+            }
+            if x > 0 {
+                value -= field[(0, y, x - 1)];
+            }
+            if y + 1 < h {
+                value += field[(1, y, x)];
+            }
+            if y > 0 {
+                value -= field[(1, y - 1, x)];
+            }
+            result[(y, x)] = value;
+        }
+    }
+    result
+}
+
+/// Total variation of `image` under `norm`.
+pub fn total_variation(image: &Array2<f64>, norm: Norm) -> f64 {
+    let g = gradient(image);
+    match norm {
+        Norm::Anisotropic => g.fold(0.0, |sum, v| sum + v.abs()),
+        Norm::Isotropic => {
+            let (dx, dy) = (g.slice(s![0, .., ..]), g.slice(s![1, .., ..]));
+            dx.iter().zip(dy.iter())
+                .map(|(a, b)| f64::sqrt(a * a + b * b))
+                .sum()
+        },
+    }
+}
+
+// Projects the dual gradient field onto the unit ball of the dual norm scaled
+// by `radius`, which is the proximal step for the conjugate of `radius·TV`.
+fn project(field: &mut Array3<f64>, radius: f64, norm: Norm) {
+    match norm {
+        Norm::Anisotropic => {
+            field.mapv_inplace(|v| v.clamp(-radius, radius));
+        },
+        Norm::Isotropic => {
+            let (_, h, w) = field.dim();
+            for y in 0 .. h {
+                for x in 0 .. w {
+                    let (a, b) = (field[(0, y, x)], field[(1, y, x)]);
+                    let scale = f64::max(1.0, f64::sqrt(a * a + b * b) / radius);
+                    field[(0, y, x)] = a / scale;
+                    field[(1, y, x)] = b / scale;
+                }
+            }
+        },
+    }
+}
+
+fn solve(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    n: usize,
+    lambda: f64,
+    options: &Options,
+    initial: Option<Array1<f64>>,
+) -> Array1<f64> {
+    let mut x = initial.unwrap_or_else(|| Array1::zeros(n * n));
+    // Rescale A to unit norm so that one step size suits both blocks of K:
+    // ½‖Ax − y‖² + λTV(x) = s²(½‖(A/s)x − y/s‖² + (λ/s²)TV(x)).
+    let norm_squared = spectral_norm_squared(a, 50);
+    if norm_squared == 0.0 {
+        return x;
+    }
+    let scale = f64::sqrt(norm_squared);
+    let y = y / scale;
+    let radius = lambda / norm_squared;
+    // ‖K‖² ≤ 1 + ‖∇‖² ≤ 9.
+    let (tau, sigma) = (0.99 / 3.0, 0.99 / 3.0);
+
+    let mut x_bar = x.clone();
+    let mut p = Array1::<f64>::zeros(a.rows());
+    let mut q = Array3::<f64>::zeros((2, n, n));
+    for _ in 0 .. options.iterations {
+        p = (&p + &((a.apply(&x_bar) / scale - &y) * sigma)) / (1.0 + sigma);
+        let image_bar = x_bar.clone().into_shape((n, n)).unwrap();
+        q = q + gradient(&image_bar) * sigma;
+        project(&mut q, radius, options.norm);
+
+        let divergence =
+            Array1::from_iter(divergence(&q).into_iter());
+        let mut next = &x - &((a.adjoint(&p) / scale - divergence) * tau);
+        if options.nonnegative {
+            next.mapv_inplace(|v| v.max(0.0));
+        }
+        let change = &next - &x;
+        x_bar = &next + &change;
+        x = next;
+        if change.dot(&change) <= TOLERANCE * TOLERANCE * x.dot(&x) {
+            break;
+        }
+    }
+    x
+}
+
+/// Recovers an `n × n` image (flattened row-major) from `y = Ax` by
+/// minimizing `½‖Ax − y‖₂² + λ TV(x)`.
+pub fn recover(
+    a: &dyn LinearOperator,
+    y: &Array1<f64>,
+    n: usize,
+    options: &Options,
+) -> Array1<f64> {
+    let lambda = options.lambda.unwrap_or_else(|| {
+        let grid = lambda_grid(lambda_max(a, y), options.candidates);
+        cross_validate_with(a, y, &grid, options.folds, |a, y, lambda, initial| {
+            solve(a, y, n, lambda, options, initial)
+        })
+    });
+    solve(a, y, n, lambda, options, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    const N: usize = 16;
+
+    // Two flat rectangles and a disc on a dark background.
+    fn piecewise_flat() -> Array2<f64> {
+        Array2::from_shape_fn((N, N), |(y, x)| {
+            let (dx, dy) = (x as f64 - 10.5, y as f64 - 10.5);
+            if (2 .. 7).contains(&x) && (3 .. 9).contains(&y) {
+                5.0
+            } else if dx * dx + dy * dy < 9.0 {
+                8.0
+            } else if (9 .. 14).contains(&x) && (1 .. 4).contains(&y) {
+                3.0
+            } else {
+                0.0
+            }
+        })
+    }
+
+    fn sensing_matrix(rows: usize) -> Array2<f64> {
+        let mut rng = StdRng::seed_from_u64(5);
+        Array2::from_shape_fn((rows, N * N), |_| {
+            if rng.gen() { 1.0 } else { -1.0 }
+        })
+    }
+
+    fn check_recovery(norm: Norm) {
+        let truth = Array1::from_iter(piecewise_flat().into_iter());
+        let a = sensing_matrix(160);
+        let y = a.apply(&truth);
+        let options = Options {
+            norm,
+            lambda: Some(1.0),
+            iterations: 3000,
+            ..Options::default()
+        };
+        let image = recover(&a, &y, N, &options);
+        let difference = &image - &truth;
+        let error = f64::sqrt(difference.dot(&difference) / truth.dot(&truth));
+        assert!(error < 0.05, "{:?} error {}", norm, error);
+    }
+
+    #[test]
+    fn divergence_is_negative_adjoint_of_gradient() {
+        let u = Array2::from_shape_fn((5, 7), |(y, x)| ((x * 3 + y) % 4) as f64);
+        let v = Array3::from_shape_fn((2, 5, 7), |(c, y, x)| {
+            ((c + 2 * x + y * y) % 5) as f64 - 2.0
+        });
+        let lhs = (gradient(&u) * &v).sum();
+        let rhs = -(u * divergence(&v)).sum();
+        assert!((lhs - rhs).abs() < 1e-9);
+    }
+
+    #[test]
+    fn recovers_piecewise_flat_image_isotropic() {
+        check_recovery(Norm::Isotropic);
+    }
+
+    #[test]
+    fn recovers_piecewise_flat_image_anisotropic() {
+        check_recovery(Norm::Anisotropic);
+    }
+}
@@ -347,7 +347,7 @@ self: super: {
     ln -s ${self.firmware-with-custom-overlays}/* .
     ln -s ${self.initrd}/initrd initrd
     ln -s ${self.monocle}/bin/raspi raspi
-    cp ${./modulator.edid} modulator.edid
+    cp ${../monocle/res/modulator.edid} modulator.edid
     ls -lLhs initrd
     cat <<EOF > config.txt
     dtoverlay=dwc2